@@ -0,0 +1,160 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Heap debugging
+//!
+//! Every allocation is surrounded by red zones filled with a canary, which
+//! are checked when it is freed. Freed memory is poisoned, and every live
+//! allocation is recorded with the return addresses of its callers.
+
+use core::alloc::Layout;
+use core::cmp::max;
+use core::ptr;
+
+use spin::Mutex;
+
+use hole::align_up;
+
+/// The size of the red zones on either side of an allocation
+pub const RED_ZONE: usize = 16;
+/// The byte that red zones are filled with
+pub const CANARY: u8 = 0xca;
+/// The byte that freed memory is filled with
+pub const POISON: u8 = 0x6b;
+/// The number of return addresses recorded for each allocation
+pub const TRACE_DEPTH: usize = 4;
+/// The most live allocations that can be recorded at once
+const MAX_TRACKED: usize = 1024;
+
+/// A live allocation
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    /// The address returned to the caller
+    pub address: usize,
+    /// The size that was requested
+    pub size: usize,
+    /// The return addresses of the innermost callers of the allocator. Unused
+    /// entries are zero.
+    pub callers: [usize; TRACE_DEPTH],
+}
+
+/// The record of every live allocation
+struct Tracker {
+    live: [Option<Allocation>; MAX_TRACKED],
+    /// The number of live allocations that did not fit in `live`
+    untracked: usize,
+}
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+    live: [None; MAX_TRACKED],
+    untracked: 0,
+});
+
+/// Returns the layout of the block that holds `layout` and its red zones, and
+/// the offset of the allocation in it.
+pub fn block_layout(layout: &Layout) -> (Layout, usize) {
+    let offset = align_up(RED_ZONE, layout.align());
+    let size = offset + layout.size() + RED_ZONE;
+    (Layout::from_size_align(size, max(layout.align(), RED_ZONE)).unwrap(), offset)
+}
+
+/// Fills the red zones of the newly allocated `block` and records it.
+/// Returns the address to give to the caller.
+#[inline(never)]
+pub unsafe fn on_alloc(block: *mut u8, layout: &Layout) -> *mut u8 {
+    let (_, offset) = block_layout(layout);
+    let address = block.offset(offset as isize);
+    ptr::write_bytes(block, CANARY, offset);
+    ptr::write_bytes(address.offset(layout.size() as isize), CANARY, RED_ZONE);
+
+    let allocation = Allocation {
+        address: address as usize,
+        size: layout.size(),
+        callers: callers(),
+    };
+    let mut tracker = TRACKER.lock();
+    match tracker.live.iter_mut().find(|a| a.is_none()) {
+        Some(slot) => *slot = Some(allocation),
+        None => tracker.untracked += 1,
+    }
+    address
+}
+
+/// Checks the red zones of the allocation at `address`, forgets it and
+/// poisons it. Returns the block that holds it.
+///
+/// # Panics
+/// If either red zone was overwritten
+pub unsafe fn on_dealloc(address: *mut u8, layout: &Layout) -> *mut u8 {
+    let (block_layout, offset) = block_layout(layout);
+    let block = address.offset(-(offset as isize));
+
+    let front = (0..offset).all(|i| *block.offset(i as isize) == CANARY);
+    let back = (0..RED_ZONE)
+        .all(|i| *address.offset((layout.size() + i) as isize) == CANARY);
+    if !front || !back {
+        panic!("Heap corruption {} the {} byte allocation at {:p}",
+               if front { "after" } else { "before" }, layout.size(), address);
+    }
+
+    {
+        let mut tracker = TRACKER.lock();
+        match tracker.live.iter_mut()
+            .find(|a| a.map_or(false, |a| a.address == address as usize)) {
+            Some(slot) => *slot = None,
+            None => tracker.untracked = tracker.untracked.saturating_sub(1),
+        }
+    }
+    ptr::write_bytes(block, POISON, block_layout.size());
+    block
+}
+
+/// Calls `f` with every live allocation that has been recorded, and returns
+/// the number of live allocations that could not be recorded.
+///
+/// `f` must not allocate.
+pub fn for_each_allocation<F>(mut f: F) -> usize
+    where F: FnMut(&Allocation)
+{
+    let tracker = TRACKER.lock();
+    for allocation in tracker.live.iter().filter_map(|a| a.as_ref()) {
+        f(allocation);
+    }
+    tracker.untracked
+}
+
+/// Returns the return addresses of the callers of the allocator by following
+/// the frame pointers. They are only kept when building with
+/// `make heapdebug=yes`.
+#[inline(never)]
+fn callers() -> [usize; TRACE_DEPTH] {
+    // `callers` and `on_alloc` are not interesting
+    const SKIP: usize = 2;
+
+    let mut callers = [0; TRACE_DEPTH];
+    let mut frame: usize;
+    unsafe {
+        asm!("mov $0, rbp" : "=r"(frame) ::: "intel");
+    }
+    for i in 0..SKIP + TRACE_DEPTH {
+        // Stacks are in the higher half, anything else is the end of the
+        // chain
+        if frame < 0xffff_8000_0000_0000 || frame % 8 != 0 {
+            break;
+        }
+        let (next, return_address) = unsafe {
+            (*(frame as *const usize), *((frame + 8) as *const usize))
+        };
+        if i >= SKIP {
+            callers[i - SKIP] = return_address;
+        }
+        frame = next;
+    }
+    callers
+}
@@ -0,0 +1,324 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use core::cmp::max;
+use core::mem::size_of;
+use core::ptr;
+
+/// A free block of memory. Each `Hole` is stored at the start of the memory
+/// it describes.
+struct Hole {
+    size: usize,
+    next: *mut Hole,
+}
+
+/// The smallest block that can be stored in the list. Blocks smaller than
+/// this cannot hold a `Hole` and are never created.
+pub const MIN_SIZE: usize = size_of::<Hole>();
+
+/// A list of free blocks sorted by address.
+pub struct HoleList {
+    /// A dummy head, its size is always zero.
+    first: Hole,
+}
+
+unsafe impl Send for HoleList {}
+
+impl HoleList {
+    /// Creates a new list without any holes
+    pub const fn empty() -> HoleList {
+        HoleList {
+            first: Hole {
+                size: 0,
+                next: 0 as *mut Hole,
+            },
+        }
+    }
+
+    /// Finds the first hole that can hold `size` bytes at `align` and removes
+    /// that part of it from the list. Returns the address of the block.
+    ///
+    /// Both `size` and `align` must be multiples of `MIN_SIZE`'s alignment and
+    /// `size` must be at least `MIN_SIZE`.
+    pub unsafe fn allocate(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut prev: *mut Hole = &mut self.first;
+
+        while !(*prev).next.is_null() {
+            let hole = (*prev).next;
+            let start = hole as usize;
+            let end = start + (*hole).size;
+
+            // Any space left in front of the block must be able to hold a hole
+            let mut aligned = align_up(start, align);
+            if aligned != start && aligned - start < MIN_SIZE {
+                aligned = align_up(start + MIN_SIZE, align);
+            }
+
+            let fits = aligned.checked_add(size).map_or(false, |e| e <= end);
+            // The same goes for any space left after the block
+            if fits && (end - (aligned + size) == 0 || end - (aligned + size) >= MIN_SIZE) {
+                let back = end - (aligned + size);
+
+                let mut next = (*hole).next;
+                if back != 0 {
+                    let back_hole = (aligned + size) as *mut Hole;
+                    ptr::write(back_hole, Hole {
+                        size: back,
+                        next: next,
+                    });
+                    next = back_hole;
+                }
+
+                if aligned != start {
+                    // Keep the front of the hole
+                    (*hole).size = aligned - start;
+                    (*hole).next = next;
+                } else {
+                    (*prev).next = next;
+                }
+                return Some(aligned);
+            }
+
+            prev = hole;
+        }
+        None
+    }
+
+    /// Returns the block `[addr, addr + size)` to the list, merging it with
+    /// any adjacent holes.
+    pub unsafe fn deallocate(&mut self, addr: usize, size: usize) {
+        let head: *mut Hole = &mut self.first;
+        let mut prev = head;
+
+        while !(*prev).next.is_null() && ((*prev).next as usize) < addr {
+            prev = (*prev).next;
+        }
+
+        let mut size = size;
+        let mut next = (*prev).next;
+        if !next.is_null() && addr + size == next as usize {
+            // Merge with the following hole
+            size += (*next).size;
+            next = (*next).next;
+        }
+
+        if prev != head && prev as usize + (*prev).size == addr {
+            // Merge with the preceding hole
+            (*prev).size += size;
+            (*prev).next = next;
+        } else {
+            let hole = addr as *mut Hole;
+            ptr::write(hole, Hole {
+                size: size,
+                next: next,
+            });
+            (*prev).next = hole;
+        }
+    }
+
+    /// Returns the number of holes, their total size and the size of the
+    /// largest one
+    pub fn stats(&self) -> (usize, usize, usize) {
+        let (mut count, mut total, mut largest) = (0, 0, 0);
+        let mut hole = self.first.next;
+        while !hole.is_null() {
+            unsafe {
+                count += 1;
+                total += (*hole).size;
+                largest = max(largest, (*hole).size);
+                hole = (*hole).next;
+            }
+        }
+        (count, total, largest)
+    }
+
+    /// Returns the address and size of the hole with the highest address
+    pub fn last(&self) -> Option<(usize, usize)> {
+        let mut hole = self.first.next;
+        if hole.is_null() {
+            return None;
+        }
+        unsafe {
+            while !(*hole).next.is_null() {
+                hole = (*hole).next;
+            }
+            Some((hole as usize, (*hole).size))
+        }
+    }
+
+    /// Cuts the last hole off at `top`.
+    ///
+    /// `top` must be inside of the last hole, and must either be its start or
+    /// leave at least `MIN_SIZE` bytes in it.
+    pub unsafe fn truncate(&mut self, top: usize) {
+        let mut prev: *mut Hole = &mut self.first;
+        assert!(!(*prev).next.is_null());
+        while !(*(*prev).next).next.is_null() {
+            prev = (*prev).next;
+        }
+        let hole = (*prev).next;
+        let start = hole as usize;
+
+        assert!(top >= start && top <= start + (*hole).size);
+        if top == start {
+            (*prev).next = ptr::null_mut();
+        } else {
+            assert!(top - start >= MIN_SIZE);
+            (*hole).size = top - start;
+        }
+    }
+}
+
+/// Align upwards. Returns the smallest x with alignment `align`
+/// such that x >= addr. The alignment must be a power of 2.
+pub fn align_up(addr: usize, align: usize) -> usize {
+    assert!(align.is_power_of_two(), "Alignment must be power of two!");
+    (addr + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use super::{HoleList, align_up};
+
+    /// Returns a list with one hole of `size` bytes, the memory behind it and
+    /// the start of the hole, which is 64 byte aligned
+    fn list(size: usize) -> (HoleList, Vec<u64>, usize) {
+        let mut memory = vec![0u64; size / 8 + 8];
+        let start = align_up(memory.as_mut_ptr() as usize, 64);
+        let mut holes = HoleList::empty();
+        unsafe {
+            holes.deallocate(start, size);
+        }
+        (holes, memory, start)
+    }
+
+    #[test]
+    fn allocate_first_fit() {
+        let (mut holes, _memory, start) = list(256);
+        unsafe {
+            assert_eq!(holes.allocate(64, 8), Some(start));
+            assert_eq!(holes.allocate(64, 8), Some(start + 64));
+        }
+        assert_eq!(holes.stats(), (1, 128, 128));
+    }
+
+    #[test]
+    fn allocate_exact_fit() {
+        let (mut holes, _memory, start) = list(128);
+        unsafe {
+            assert_eq!(holes.allocate(128, 8), Some(start));
+            assert_eq!(holes.allocate(16, 8), None);
+        }
+        assert_eq!(holes.stats(), (0, 0, 0));
+        assert_eq!(holes.last(), None);
+    }
+
+    #[test]
+    fn allocate_aligned() {
+        let (mut holes, _memory, start) = list(256);
+        unsafe {
+            holes.allocate(32, 8).unwrap();
+            assert_eq!(holes.allocate(32, 64), Some(start + 64));
+        }
+        // The space in front of the block is kept
+        assert_eq!(holes.stats(), (2, 192, 160));
+    }
+
+    #[test]
+    fn allocate_aligned_past_small_front() {
+        let (mut holes, _memory, start) = list(256);
+        unsafe {
+            holes.allocate(56, 8).unwrap();
+            // Aligning to the next 64 bytes would leave 8 bytes in front,
+            // which cannot hold a hole
+            assert_eq!(holes.allocate(16, 64), Some(start + 128));
+        }
+        assert_eq!(holes.stats(), (2, 184, 112));
+    }
+
+    #[test]
+    fn allocate_skips_small_back() {
+        let (mut holes, _memory, start) = list(256);
+        unsafe {
+            let a = holes.allocate(64, 8).unwrap();
+            holes.allocate(64, 8).unwrap();
+            holes.deallocate(a, 64);
+            // 56 bytes of the first hole would leave 8 bytes behind
+            assert_eq!(holes.allocate(56, 8), Some(start + 128));
+        }
+        assert_eq!(holes.stats(), (2, 136, 72));
+    }
+
+    #[test]
+    fn deallocate_merges_with_next() {
+        let (mut holes, _memory, start) = list(256);
+        unsafe {
+            let a = holes.allocate(64, 8).unwrap();
+            holes.allocate(64, 8).unwrap();
+            let c = holes.allocate(64, 8).unwrap();
+            holes.deallocate(a, 64);
+            assert_eq!(holes.stats(), (2, 128, 64));
+            holes.deallocate(c, 64);
+        }
+        assert_eq!(holes.stats(), (2, 192, 128));
+        assert_eq!(holes.last(), Some((start + 128, 128)));
+    }
+
+    #[test]
+    fn deallocate_merges_with_previous() {
+        let (mut holes, _memory, start) = list(256);
+        unsafe {
+            let a = holes.allocate(64, 8).unwrap();
+            let b = holes.allocate(64, 8).unwrap();
+            holes.allocate(64, 8).unwrap();
+            holes.deallocate(a, 64);
+            holes.deallocate(b, 64);
+        }
+        assert_eq!(holes.stats(), (2, 192, 128));
+        assert_eq!(holes.last(), Some((start + 192, 64)));
+    }
+
+    #[test]
+    fn deallocate_merges_with_both() {
+        let (mut holes, _memory, start) = list(256);
+        unsafe {
+            let a = holes.allocate(64, 8).unwrap();
+            let b = holes.allocate(64, 8).unwrap();
+            let c = holes.allocate(64, 8).unwrap();
+            holes.deallocate(c, 64);
+            holes.deallocate(a, 64);
+            holes.deallocate(b, 64);
+        }
+        assert_eq!(holes.stats(), (1, 256, 256));
+        assert_eq!(holes.last(), Some((start, 256)));
+    }
+
+    #[test]
+    fn truncate_last_hole() {
+        let (mut holes, _memory, start) = list(256);
+        unsafe {
+            holes.allocate(64, 8).unwrap();
+            holes.truncate(start + 128);
+        }
+        assert_eq!(holes.last(), Some((start + 64, 64)));
+        unsafe {
+            holes.truncate(start + 64);
+        }
+        assert_eq!(holes.last(), None);
+    }
+
+    #[test]
+    fn align_up_rounds_to_alignment() {
+        assert_eq!(align_up(0, 8), 0);
+        assert_eq!(align_up(1, 8), 8);
+        assert_eq!(align_up(64, 64), 64);
+        assert_eq!(align_up(65, 64), 128);
+    }
+}
@@ -7,16 +7,241 @@
 // This file may not be copied, modified, or distributed
 // except according to those terms.
 
+#![feature(const_fn)]
+#![cfg_attr(feature = "debug", feature(asm))]
 #![no_std]
 
-extern crate linked_list_allocator;
+extern crate spin;
+#[cfg(test)]
+#[macro_use]
+extern crate std;
 
-use linked_list_allocator::LockedHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp::max;
+use core::mem::align_of;
+use core::ptr;
 
-// TODO use own mutex instead of spinlock in `LockedHeap`
-#[global_allocator]
+use spin::Mutex;
+
+use hole::{HoleList, MIN_SIZE, align_up};
+
+/// The sorted list of free blocks
+mod hole;
+/// Red zones, poisoning and leak tracking
+#[cfg(feature = "debug")]
+mod debug;
+
+#[cfg(feature = "debug")]
+pub use debug::{Allocation, for_each_allocation, RED_ZONE, CANARY, POISON, TRACE_DEPTH};
+
+// The tests run on the host, which has its own allocator
+#[cfg_attr(not(test), global_allocator)]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
+/// Initializes the heap to `[start, start + size)`. The heap never shrinks
+/// below this size.
 pub unsafe fn init(start: usize, size: usize) {
-    ALLOCATOR.lock().init(start, size);
+    ALLOCATOR.0.lock().init(start, size);
+}
+
+/// Sets the functions used to grow and shrink the heap on demand.
+pub fn set_backing(backing: Backing) {
+    ALLOCATOR.0.lock().backing = Some(backing);
+}
+
+/// Returns the current size of the heap in bytes
+pub fn size() -> usize {
+    ALLOCATOR.0.lock().size
+}
+
+/// A snapshot of heap usage
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// The size of the heap in bytes
+    pub size: usize,
+    /// The number of free bytes
+    pub free: usize,
+    /// The size of the largest free block
+    pub largest_free: usize,
+    /// The number of free blocks
+    pub holes: usize,
+}
+
+/// Returns the current heap usage
+pub fn stats() -> HeapStats {
+    let heap = ALLOCATOR.0.lock();
+    let (holes, free, largest_free) = heap.holes.stats();
+    HeapStats {
+        size: heap.size,
+        free: free,
+        largest_free: largest_free,
+        holes: holes,
+    }
+}
+
+/// The memory behind the heap.
+///
+/// The heap is always a contiguous range of virtual memory, so it can only
+/// grow or shrink at its top.
+#[derive(Clone, Copy)]
+pub struct Backing {
+    /// Maps up to `pages` pages starting at `top` and returns the number of
+    /// pages that were mapped.
+    pub grow: fn(top: usize, pages: usize) -> usize,
+    /// If set, unmaps `pages` pages starting at `top` whenever they become
+    /// free at the top of the heap. Returns `false` if the pages could not be
+    /// unmapped, they then stay part of the heap.
+    pub shrink: Option<fn(top: usize, pages: usize) -> bool>,
+    /// The size of the pages used by `grow` and `shrink`
+    pub page_size: usize,
+}
+
+/// A first fit heap that may grow and shrink.
+struct Heap {
+    bottom: usize,
+    size: usize,
+    /// The size that the heap was initialized to
+    min_size: usize,
+    holes: HoleList,
+    backing: Option<Backing>,
+}
+
+impl Heap {
+    const fn empty() -> Heap {
+        Heap {
+            bottom: 0,
+            size: 0,
+            min_size: 0,
+            holes: HoleList::empty(),
+            backing: None,
+        }
+    }
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        assert!(self.size == 0, "The heap can only be initialized once");
+        assert!(start % align_of::<usize>() == 0 && size >= MIN_SIZE);
+        self.bottom = start;
+        self.size = size;
+        self.min_size = size;
+        self.holes.deallocate(start, size);
+    }
+
+    fn top(&self) -> usize {
+        self.bottom + self.size
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = block(&layout);
+        loop {
+            if let Some(addr) = unsafe { self.holes.allocate(size, align) } {
+                return addr as *mut u8;
+            }
+            // Worst case the new memory is not merged with any hole and
+            // needs to be padded to be aligned
+            if !self.grow(size + align + MIN_SIZE) {
+                return ptr::null_mut();
+            }
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block(&layout);
+        self.holes.deallocate(ptr as usize, size);
+        self.trim();
+    }
+
+    /// Asks the backing to map at least `bytes` more bytes to the heap.
+    /// Returns `false` if nothing could be mapped.
+    fn grow(&mut self, bytes: usize) -> bool {
+        let backing = match self.backing {
+            Some(backing) => backing,
+            None => return false,
+        };
+        let pages = (bytes + backing.page_size - 1) / backing.page_size;
+
+        let mapped = (backing.grow)(self.top(), pages);
+        if mapped == 0 {
+            return false;
+        }
+        unsafe {
+            self.holes.deallocate(self.top(), mapped * backing.page_size);
+        }
+        self.size += mapped * backing.page_size;
+        true
+    }
+
+    /// Returns any free pages at the top of the heap to the backing
+    fn trim(&mut self) {
+        let (backing, shrink) = match self.backing {
+            Some(backing) => match backing.shrink {
+                Some(shrink) => (backing, shrink),
+                None => return,
+            },
+            None => return,
+        };
+        let top = self.top();
+        let (start, size) = match self.holes.last() {
+            Some((start, size)) if start + size == top => (start, size),
+            _ => return,
+        };
+
+        let floor = max(start, self.bottom + self.min_size);
+        let mut new_top = align_up(floor, backing.page_size);
+        // Whatever is left of the hole must still be able to hold a hole
+        if new_top != start && new_top - start < MIN_SIZE {
+            new_top += backing.page_size;
+        }
+
+        if new_top < top && shrink(new_top, (top - new_top) / backing.page_size) {
+            unsafe {
+                self.holes.truncate(new_top);
+            }
+            self.size = new_top - self.bottom;
+        }
+    }
+}
+
+/// Returns the size and alignment of the block that holds `layout`.
+fn block(layout: &Layout) -> (usize, usize) {
+    let size = max(align_up(layout.size(), align_of::<usize>()), MIN_SIZE);
+    let align = max(layout.align(), align_of::<usize>());
+    (size, align)
+}
+
+/// A `Heap` behind a spinlock
+pub struct LockedHeap(Mutex<Heap>);
+
+impl LockedHeap {
+    const fn empty() -> LockedHeap {
+        LockedHeap(Mutex::new(Heap::empty()))
+    }
+}
+
+#[cfg(not(feature = "debug"))]
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().deallocate(ptr, layout)
+    }
+}
+
+#[cfg(feature = "debug")]
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (block_layout, _) = debug::block_layout(&layout);
+        let block = self.0.lock().allocate(block_layout);
+        if block.is_null() {
+            return block;
+        }
+        debug::on_alloc(block, &layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (block_layout, _) = debug::block_layout(&layout);
+        let block = debug::on_dealloc(ptr, &layout);
+        self.0.lock().deallocate(block, block_layout)
+    }
 }
@@ -344,16 +344,18 @@ kernel_table:
 
 ; The multiboot standard does not define the value of the stack pointer register
 ; (esp) and it is up to the kernel to provide a stack. This allocates room for a
-; small stack by creating a symbol at the bottom of it, then allocating 64
-; bytes for it, and finally creating a symbol at the top. The stack grows
-; downwards on x86. The stack is in its own section so it can be marked nobits,
+; small stack by creating a symbol at the bottom of it, then allocating 10
+; pages for it, and finally creating a symbol at the top. The boot page tables
+; above are reclaimed after boot, so they are not part of the stack. The stack
+; grows downwards on x86. The stack is in its own section so it can be marked nobits,
 ; which means the kernel file is smaller because it does not contain an
 ; uninitialized stack. The stack on x86 must be 16-byte aligned according to the
 ; System V ABI standard and de-facto extensions. The compiler will assume the
 ; stack is properly aligned and failure to align the stack will result in
 ; undefined behavior.
 align 16
+global kstack_bottom
 kstack_bottom:
-	resb 4096 * 5
+	resb 4096 * 10
 global kstack_top
 kstack_top:
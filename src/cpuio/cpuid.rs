@@ -0,0 +1,66 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Querying CPU features with the `cpuid` instruction
+
+/// The registers returned by `cpuid`
+#[derive(Debug, Clone, Copy)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Executes `cpuid` for `leaf` and `subleaf`
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(subleaf)
+             :: "volatile");
+    }
+    CpuidResult { eax: eax, ebx: ebx, ecx: ecx, edx: edx }
+}
+
+/// Returns true if the page attribute table is supported
+pub fn has_pat() -> bool {
+    cpuid(1, 0).edx & (1 << 16) != 0
+}
+
+/// Returns true if 1GiB pages are supported
+pub fn has_giant_pages() -> bool {
+    cpuid(0x8000_0000, 0).eax >= 0x8000_0001 && cpuid(0x8000_0001, 0).edx & (1 << 26) != 0
+}
+
+/// Returns the registers of the structured extended feature leaf, or `None`
+/// if the CPU does not have it
+fn extended_features() -> Option<CpuidResult> {
+    if cpuid(0, 0).eax >= 7 {
+        Some(cpuid(7, 0))
+    } else {
+        None
+    }
+}
+
+/// Returns true if supervisor mode execution prevention is supported
+pub fn has_smep() -> bool {
+    extended_features().map_or(false, |r| r.ebx & (1 << 7) != 0)
+}
+
+/// Returns true if supervisor mode access prevention is supported
+pub fn has_smap() -> bool {
+    extended_features().map_or(false, |r| r.ebx & (1 << 20) != 0)
+}
+
+/// Returns true if user mode instruction prevention is supported
+pub fn has_umip() -> bool {
+    extended_features().map_or(false, |r| r.ecx & (1 << 2) != 0)
+}
@@ -33,6 +33,7 @@ macro_rules! serial_print {
 
 pub mod port;
 pub mod serial;
+pub mod cpuid;
 
 pub fn init() {
     COM1.lock().init();
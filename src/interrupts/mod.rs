@@ -55,6 +55,9 @@
 #![allow(dead_code)]
 #![allow(unreachable_code)]
 
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use spin::{Mutex, Once};
 
 use x86_64::VirtualAddress;
@@ -112,11 +115,19 @@ static IDT: IrqLock<Idt> = IrqLock::new(Idt::new());
 static PIC: Mutex<ChainedPICs> = Mutex::new(unsafe { ChainedPICs::new(0x20, 0x28) });
 
 const DF_TSS_INDEX: u16 = 0;
+const PF_TSS_INDEX: u16 = 1;
 #[cfg(feature = "test")]
-const TEST_TSS_INDEX: u16 = 1;
+const TEST_TSS_INDEX: u16 = 2;
 
 pub const SLEEP_INT: u8 = 0x22;
 pub const EXIT_INT: u8 = 0x23;
+pub const JOIN_INT: u8 = 0x24;
+
+/// Set while the page fault handler runs. Page faults always start at the top
+/// of the same IST stack, so a nested fault has overwritten the fault it
+/// interrupted.
+// FIXME make CPU local
+static IN_PAGE_FAULT: AtomicBool = AtomicBool::new(false);
 
 /// Static Task State Segment
 static TSS: Once<TaskStateSegment> = Once::new();
@@ -133,12 +144,23 @@ pub fn init() {
 
         tss.interrupt_stack_table[DF_TSS_INDEX as usize] =
             VirtualAddress(double_fault_stack.top());
+        // The stack is used for as long as the kernel runs
+        mem::forget(double_fault_stack);
+
+        // Page faults get their own stack so that a thread overflowing into
+        // its guard page does not double fault
+        let page_fault_stack = memory::alloc_stack(2)
+            .expect("Could not allocate page fault stack");
+        tss.interrupt_stack_table[PF_TSS_INDEX as usize] =
+            VirtualAddress(page_fault_stack.top());
+        mem::forget(page_fault_stack);
 
         #[cfg(feature = "test")] {
             let test_stack = memory::alloc_stack(1)
                 .expect("Could not allocate test stack");
             tss.interrupt_stack_table[TEST_TSS_INDEX as usize] =
                 VirtualAddress(test_stack.top());
+            mem::forget(test_stack);
         }
 
         tss
@@ -180,12 +202,16 @@ pub fn init() {
             .set_stack_index(DF_TSS_INDEX);
     }
     idt.set_handler(0xD, handler_error_code!(gp_handler));
-    idt.set_handler(0xE, handler_error_code!(pf_handler));
+    unsafe {
+        idt.set_handler(0xE, handler_error_code!(pf_handler))
+            .set_stack_index(PF_TSS_INDEX);
+    }
     // PIC handlers
     idt.set_handler(0x20, handler!(timer_handler));
     idt.set_handler(0x21, handler!(kb_handler));
     idt.set_handler(SLEEP_INT, handler!(sleep_handler));
     idt.set_handler(EXIT_INT, handler!(exit_handler));
+    idt.set_handler(JOIN_INT, handler!(join_handler));
 
     // Set up the PIC and initialize interrupts.
     unsafe {
@@ -263,9 +289,35 @@ extern "C" fn gp_handler(c: &'static Context) -> &'static Context {
 /// non-executable page.
 /// + A protection check (privileges, read/write) failed.
 /// + A reserved bit in the page directory or table entries is set to 1.
+///
+/// Faults in lazily backed memory are resolved and the faulting context is
+/// resumed. A thread that overflows into the guard page below its stack is
+/// killed, any other fault is unrecoverable. A fault within this handler is
+/// treated as a double fault.
 extern "C" fn pf_handler(context: &'static Context) -> &'static Context {
-    panic!("EXCEPTION PAGE FAULT\nerror_code: 0b{:b}\nAddress that caused the fault: {:#?}\n{:#?}",
-           context.error_code, registers::control_regs::cr2(), context.stack_frame);
+    if IN_PAGE_FAULT.swap(true, Ordering::Relaxed) {
+        return df_handler(context);
+    }
+    let next = resolve_page_fault(context);
+    IN_PAGE_FAULT.store(false, Ordering::Relaxed);
+    next
+}
+
+/// Resolves a page fault, returning the context to resume
+fn resolve_page_fault(context: &'static Context) -> &'static Context {
+    let address = registers::control_regs::cr2().0 as usize;
+    let error = memory::PageFaultError::from_bits_truncate(context.error_code as u64);
+    if memory::handle_page_fault(address, error) {
+        return context;
+    }
+    if let Some(id) = scheduler::stack_overflow(address) {
+        println!("Thread {} overflowed its stack at {:#x}, killing it",
+                 id, context.stack_frame.instruction_pointer);
+        return scheduler::sched_exit(context, scheduler::KILLED);
+    }
+    panic!("EXCEPTION PAGE FAULT\nerror_code: 0b{:b}\nAddress that caused the fault: {:#?}\n{}\n{:#?}",
+           context.error_code, registers::control_regs::cr2(), memory::walk(address),
+           context.stack_frame);
     context
 }
 
@@ -348,7 +400,12 @@ extern "C" fn sleep_handler(c: &'static Context) -> &'static Context {
 }
 
 extern "C" fn exit_handler(c: &'static Context) -> &'static Context {
-    scheduler::sched_exit(c)
+    scheduler::sched_exit(c, c.regs.rax)
+}
+
+extern "C" fn join_handler(c: &'static Context) -> &'static Context {
+    // The joining thread keeps the exit status alive
+    unsafe { scheduler::sched_join(c, c.regs.rax) }
 }
 
 #[cfg(feature = "test")]
@@ -365,32 +422,25 @@ pub mod tests {
             }
 
             use scheduler;
-            use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
             use super::context::Context;
 
-            // XXX we don't have `thread_wait()`, so wait on a thread through
-            // an atomic int
-            static WAIT: AtomicUsize = ATOMIC_USIZE_INIT;
+            // The exit status of the thread
             const FAILURE_MAGIC: usize = 2;
             const SUCCESS_MAGIC: usize = 1;
 
             // Run the block in a new thread
             #[allow(unused_unsafe)]
-            extern "C" fn interrupt_thread() {
+            extern "C" fn interrupt_thread() -> usize {
                 unsafe {
                     $func
                 }
                 // The block has fallen through, indicate failure.
-                WAIT.store(FAILURE_MAGIC, Ordering::Release);
-                // A return is an implicit exit
+                FAILURE_MAGIC
             }
             extern "C" fn tmp_handler(c: &'static Context) -> &'static Context {
-                WAIT.store(SUCCESS_MAGIC, Ordering::Release);
-                scheduler::sched_exit(c)
+                scheduler::sched_exit(c, SUCCESS_MAGIC)
             }
 
-            WAIT.store(0, Ordering::Release);
-
             // If this fails, $vector is not a valid expression
             let int: u8 = $vector;
 
@@ -411,17 +461,10 @@ pub mod tests {
                 };
             }
 
-            scheduler::add(interrupt_thread)
-                .expect("Could not generate a new thread to test interrupts");
-
-            // spin until the block is complete or interrupted
-            let mut res;
-            while {
-                res = WAIT.load(Ordering::Acquire);
-                res == 0
-            } {
-                scheduler::thread_yield();
-            }
+            // wait until the block is complete or interrupted
+            let res = scheduler::add(interrupt_thread)
+                .expect("Could not generate a new thread to test interrupts")
+                .join();
 
             // Restore the old handler & TSS if they existed and hope that
             // nothing interrupted into the new handler except for the thread
@@ -453,6 +496,8 @@ pub mod tests {
 
     pub fn run() {
         test_interrupts();
+        test_double_fault();
+        test_stack_overflow();
         //test_no_interrupts();
     }
 
@@ -474,13 +519,13 @@ pub mod tests {
             asm!("ud2" :::: "intel", "volatile");
         }), "#UD not caught after an invalid opcode execution");
 
-        tap.assert_tap(assert_throws!(0x8, {
+        tap.assert_tap(assert_throws!(0xE, {
             #[allow(unconditional_recursion)]
             fn stack_overflow() {
                 stack_overflow();
             }
             stack_overflow();
-        }), "#DF not caught after a stack overflow");
+        }), "#PF not caught after a stack overflow");
 
         tap.assert_tap(assert_throws!(0xD, {
             asm!("
@@ -497,6 +542,47 @@ pub mod tests {
         }), "#PF not caught after a NULL dereference");
     }
 
+    fn test_double_fault() {
+        let mut tap = TestGroup::new(1);
+        tap.diagnostic("Testing double faults");
+
+        // Without its own stack, a page fault cannot be delivered on a stack
+        // that has overflowed
+        let (handler, index) = {
+            let entry = super::IDT.lock().get_handler(0xE);
+            (entry.func().unwrap(), entry.options().get_stack_index().unwrap())
+        };
+        super::IDT.lock().set_handler(0xE, handler);
+
+        tap.assert_tap(assert_throws!(0x8, {
+            #[allow(unconditional_recursion)]
+            fn stack_overflow() {
+                stack_overflow();
+            }
+            stack_overflow();
+        }), "#DF not caught after a stack overflow");
+
+        unsafe { super::IDT.lock().set_handler(0xE, handler).set_stack_index(index) };
+    }
+
+    fn test_stack_overflow() {
+        let mut tap = TestGroup::new(1);
+        tap.diagnostic("Testing stack overflow recovery");
+        let status = ::scheduler::add(overflow_thread)
+            .expect("Could not create a thread to overflow")
+            .join();
+        tap.assert_tap(status == ::scheduler::KILLED,
+                       "Thread that overflowed its stack was not killed");
+    }
+    extern "C" fn overflow_thread() -> usize {
+        #[allow(unconditional_recursion)]
+        fn stack_overflow() {
+            stack_overflow();
+        }
+        stack_overflow();
+        0
+    }
+
     fn test_no_interrupts() {
         let mut tap = TestGroup::new(0x18);
         tap.diagnostic("Making sure exceptions are not happening");
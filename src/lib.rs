@@ -16,6 +16,7 @@
 #![feature(asm, naked_functions, core_intrinsics)]
 #![feature(abi_x86_interrupt)]
 #![feature(ptr_internals)]
+#![feature(try_reserve)]
 #![no_std]
 
 // crates.io crates
@@ -97,6 +98,10 @@ pub extern "C" fn rust_main(multiboot_info_address: usize) -> ! {
     // Initialize the serial port
     cpuio::init();
 
+    // Initialization is done, so memory only used during boot can be reused
+    let reclaimed = memory::reclaim_boot_memory(boot_info);
+    println!("Reclaimed {} KiB of boot memory", reclaimed / 1024);
+
     println!("Try to write some things!");
     vga_buffer::change_color(vga_buffer::Color::White, vga_buffer::Color::Black);
 
@@ -127,6 +132,9 @@ fn run_tests() {
     smp::tests::run();
     interrupts::tests::run();
     cpuio::tests::run();
+    memory::print_stats();
+    #[cfg(feature = "heap-debug")]
+    memory::heap_leak_report();
 }
 
 #[allow(non_snake_case)]
@@ -143,7 +151,14 @@ fn eh_personality() {}
 use core::alloc::Layout;
 /// Runs when the allocator is out of memory
 #[lang = "oom"]
-fn oom(_: Layout) -> ! {
+fn oom(layout: Layout) -> ! {
+    // The allocation may have been made while printing
+    if let Some(mut writer) = vga_buffer::WRITER.try_lock() {
+        let _ = memory::write_oom_report(&mut *writer, layout);
+    }
+    if let Some(mut serial) = cpuio::COM1.try_lock() {
+        let _ = memory::write_oom_report(&mut *serial, layout);
+    }
     panic!("Error, out of memory");
 }
 
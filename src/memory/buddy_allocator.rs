@@ -0,0 +1,503 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use core::cmp::{max, min};
+use core::mem::size_of;
+
+use memory::{Frame, FrameAllocate, FrameDeallocate};
+use memory::paging::{self, Page, ActivePageTable};
+use memory::zone::{Zone, ZONE_COUNT};
+
+use rlibc;
+use memory::PAGE_SIZE;
+
+type BitmapEntry = usize;
+const ENTRY_BITS: usize = size_of::<BitmapEntry>() * 8;
+/// The number of extra references to a frame
+type RefCount = u16;
+
+/// The largest block tracked by the allocator is `2^MAX_ORDER` frames (4MiB)
+pub const MAX_ORDER: usize = 10;
+/// The bitmaps start at 0o177777_777_777_000_000_0000, right above the
+/// kernel.
+pub const BUDDY_BASE: usize = 0o177777_777_777_000_000_0000;
+/// The virtual space reserved for the bitmap of each order. This is enough
+/// to track 1TiB of physical memory.
+const ORDER_STRIDE: usize = 32 * 1024 * 1024;
+/// The reference counts start right after the bitmaps
+const REFS_BASE: usize = BUDDY_BASE + (MAX_ORDER + 1) * ORDER_STRIDE;
+/// The virtual space reserved for the reference counts, enough for 1TiB of
+/// physical memory.
+const REFS_SIZE: usize = 512 * 1024 * 1024;
+
+/// Returns the smallest order of block that holds `count` frames
+fn order_of(count: usize) -> usize {
+    count.next_power_of_two().trailing_zeros() as usize
+}
+
+/// A buddy allocator for physical frames
+///
+/// Free blocks of `2^order` frames are tracked with one bitmap per order. A
+/// block of order `n` is always aligned to `2^n` frames, and is merged with
+/// its buddy whenever both are free.
+///
+/// Blocks are counted separately for each `Zone`, so that allocations can
+/// be restricted to low physical memory.
+///
+/// Single frames may be shared, each frame has a count of the references to
+/// it beyond the first. A shared frame is only freed once every reference is
+/// deallocated.
+pub struct BuddyAllocator {
+    /// The number of mapped entries in each bitmap
+    len: [usize; MAX_ORDER + 1],
+    /// The number of mapped reference counts
+    refs_len: usize,
+    /// The number of free blocks of each order in each zone
+    free: [[usize; MAX_ORDER + 1]; ZONE_COUNT],
+    /// The entry to start looking for a free block in, for each order in each
+    /// zone
+    hint: [[usize; MAX_ORDER + 1]; ZONE_COUNT],
+    /// The number of frames given to the allocator in each zone
+    total: [usize; ZONE_COUNT],
+}
+
+impl BuddyAllocator {
+    /// Create a new BuddyAllocator
+    ///
+    /// Each frame in `allocator` is consumed to create pages for the bitmaps
+    /// or is placed in the bitmaps. The BuddyAllocator does not allocate ever
+    /// after this function completes, therefore it can be used safely in
+    /// conjunction with an ActivePageTable.
+    pub fn new<FA>(mut allocator: FA, page_table: &mut ActivePageTable) -> BuddyAllocator
+        where FA: FrameAllocate
+    {
+        let mut buddy = BuddyAllocator {
+            len: [0; MAX_ORDER + 1],
+            refs_len: 0,
+            free: [[0; MAX_ORDER + 1]; ZONE_COUNT],
+            hint: [[0; MAX_ORDER + 1]; ZONE_COUNT],
+            total: [0; ZONE_COUNT],
+        };
+
+        while let Some(frame) = allocator.allocate_frame() {
+            // Make sure that every bitmap can hold the frame, then free it
+            for order in 0..MAX_ORDER + 1 {
+                let entries = (frame.0 >> order) / ENTRY_BITS + 1;
+                buddy.extend(order, entries, page_table, &mut allocator);
+            }
+            buddy.extend_refs(frame.0 + 1, page_table, &mut allocator);
+            buddy.total[Zone::containing(frame.0) as usize] += 1;
+            buddy.free_block(frame.0, 0);
+        }
+        buddy
+    }
+
+    /// Maps and zeroes pages of the bitmap of `order` until it holds at least
+    /// `entries` entries.
+    fn extend<FA>(&mut self,
+                  order: usize,
+                  entries: usize,
+                  page_table: &mut ActivePageTable,
+                  allocator: &mut FA)
+        where FA: FrameAllocate
+    {
+        while self.len[order] < entries {
+            let addr = self.bitmap(order) as usize
+                + self.len[order] * size_of::<BitmapEntry>();
+            assert!(addr + PAGE_SIZE <= BUDDY_BASE + (order + 1) * ORDER_STRIDE,
+                    "Too much physical memory for the buddy allocator");
+
+            // Map and zero the page
+            let page = Page::containing_address(addr);
+            page_table.map(page, paging::EntryFlags::WRITABLE | paging::EntryFlags::NO_EXECUTE,
+                           allocator);
+            unsafe {
+                rlibc::memset(page.start_address() as *mut u8, 0, PAGE_SIZE);
+            }
+            self.len[order] += PAGE_SIZE / size_of::<BitmapEntry>();
+        }
+    }
+
+    /// Maps and zeroes pages of the reference counts until there are at least
+    /// `count` of them.
+    fn extend_refs<FA>(&mut self,
+                       count: usize,
+                       page_table: &mut ActivePageTable,
+                       allocator: &mut FA)
+        where FA: FrameAllocate
+    {
+        while self.refs_len < count {
+            let addr = REFS_BASE + self.refs_len * size_of::<RefCount>();
+            assert!(addr + PAGE_SIZE <= REFS_BASE + REFS_SIZE,
+                    "Too much physical memory for the buddy allocator");
+
+            let page = Page::containing_address(addr);
+            page_table.map(page, paging::EntryFlags::WRITABLE | paging::EntryFlags::NO_EXECUTE,
+                           allocator);
+            unsafe {
+                rlibc::memset(page.start_address() as *mut u8, 0, PAGE_SIZE);
+            }
+            self.refs_len += PAGE_SIZE / size_of::<RefCount>();
+        }
+    }
+
+    /// Returns the extra reference count of `frame`, or `None` if the frame
+    /// is past the end of memory
+    fn refs(&self, frame: usize) -> Option<&mut RefCount> {
+        if frame < self.refs_len {
+            Some(unsafe { &mut *(REFS_BASE as *mut RefCount).offset(frame as isize) })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the start of the bitmap of `order`
+    fn bitmap(&self, order: usize) -> *mut BitmapEntry {
+        (BUDDY_BASE + order * ORDER_STRIDE) as *mut BitmapEntry
+    }
+
+    /// Returns the entry and bit that hold the block of `order` starting at
+    /// `frame`
+    fn place(&self, order: usize, frame: usize) -> (&mut BitmapEntry, usize) {
+        let index = frame >> order;
+        assert!(index / ENTRY_BITS < self.len[order]);
+        let entry = unsafe {
+            &mut *self.bitmap(order).offset((index / ENTRY_BITS) as isize)
+        };
+        (entry, index % ENTRY_BITS)
+    }
+
+    fn is_free(&self, order: usize, frame: usize) -> bool {
+        let (entry, bit) = self.place(order, frame);
+        *entry & (1 << bit) != 0
+    }
+
+    fn set_free(&mut self, order: usize, frame: usize, free: bool) {
+        let (entry, bit) = self.place(order, frame);
+        if free {
+            *entry |= 1 << bit;
+        } else {
+            *entry &= !(1 << bit);
+        }
+    }
+
+    /// Frees the block of `order` starting at `frame`, merging it with its
+    /// buddies for as long as they are free.
+    fn free_block(&mut self, mut frame: usize, mut order: usize) {
+        assert!(!self.is_free(order, frame), "Frame {:#x} freed twice", frame);
+        // Buddies are always in the same zone
+        let zone = Zone::containing(frame) as usize;
+
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if !self.is_free(order, buddy) {
+                break;
+            }
+            self.set_free(order, buddy, false);
+            self.free[zone][order] -= 1;
+
+            frame &= !(1 << order);
+            order += 1;
+        }
+        self.set_free(order, frame, true);
+        self.free[zone][order] += 1;
+    }
+
+    /// Frees every frame in `[start, end)` using the largest blocks possible
+    fn free_range(&mut self, start: usize, end: usize) {
+        let mut frame = start;
+        while frame < end {
+            let mut order = min(frame.trailing_zeros() as usize, MAX_ORDER);
+            while frame + (1 << order) > end {
+                order -= 1;
+            }
+            self.free_block(frame, order);
+            frame += 1 << order;
+        }
+    }
+
+    /// Removes a free block of `order` from the bitmap and returns its first
+    /// frame. Larger blocks are split if there are no blocks of `order`.
+    ///
+    /// The block is taken from `zone`, or from its fallback zones if `zone`
+    /// has no large enough blocks.
+    fn allocate_block(&mut self, order: usize, zone: Zone) -> Option<usize> {
+        let (zone, mut current) = zone.fallback().iter()
+            .filter_map(|&zone| {
+                (order..MAX_ORDER + 1)
+                    .find(|&o| self.free[zone as usize][o] > 0)
+                    .map(|o| (zone, o))
+            })
+            .next()?;
+
+        let frame = self.take_block(current, zone);
+        // Split the block, freeing each upper half
+        while current > order {
+            current -= 1;
+            self.set_free(current, frame | (1 << current), true);
+            self.free[zone as usize][current] += 1;
+        }
+        Some(frame)
+    }
+
+    /// Finds a free block of `order` in `zone` and marks it as used
+    fn take_block(&mut self, order: usize, zone: Zone) -> usize {
+        // The blocks of `zone` that are in the bitmap, `[first, last)`
+        let (start, end) = zone.frames();
+        let first = start >> order;
+        let last = min(end >> order, self.len[order] * ENTRY_BITS);
+        assert!(first < last);
+
+        let first_entry = first / ENTRY_BITS;
+        let last_entry = (last - 1) / ENTRY_BITS;
+        let entries = last_entry - first_entry + 1;
+
+        let hint = self.hint[zone as usize][order];
+        let start = if first_entry <= hint && hint <= last_entry {
+            hint - first_entry
+        } else {
+            0
+        };
+
+        for i in 0..entries {
+            let index = first_entry + (start + i) % entries;
+            // Mask off any blocks in the entry that are not in `zone`
+            let low = if index == first_entry { first % ENTRY_BITS } else { 0 };
+            let high = if index == last_entry { (last - 1) % ENTRY_BITS + 1 } else { ENTRY_BITS };
+            let mask = (!0 >> (ENTRY_BITS - high)) & (!0 << low);
+
+            let entry = unsafe {
+                &mut *self.bitmap(order).offset(index as isize)
+            };
+            if *entry & mask != 0 {
+                let bit = (*entry & mask).trailing_zeros() as usize;
+                *entry &= !(1 << bit);
+                self.free[zone as usize][order] -= 1;
+                self.hint[zone as usize][order] = index;
+                return (index * ENTRY_BITS + bit) << order;
+            }
+        }
+        unreachable!("Buddy allocator free count is wrong");
+    }
+
+    /// Allocates `count` physically contiguous frames. The first frame is
+    /// aligned to `align` frames, which must be a power of two.
+    ///
+    /// Returns the first frame, or `None` if no such run is available.
+    pub fn allocate_frames(&mut self, count: usize, align: usize) -> Option<Frame> {
+        self.allocate_frames_in(count, align, Zone::Normal)
+    }
+
+    /// Allocates a single frame from `zone` or one of its fallbacks
+    pub fn allocate_frame_in(&mut self, zone: Zone) -> Option<Frame> {
+        self.allocate_frames_in(1, 1, zone)
+    }
+
+    /// Allocates `count` physically contiguous frames from `zone` or one of
+    /// its fallbacks. The first frame is aligned to `align` frames, which must
+    /// be a power of two.
+    ///
+    /// Returns the first frame, or `None` if no such run is available.
+    pub fn allocate_frames_in(&mut self,
+                              count: usize,
+                              align: usize,
+                              zone: Zone) -> Option<Frame> {
+        assert!(count > 0, "Cannot allocate zero frames");
+        assert!(align.is_power_of_two(), "Alignment must be a power of two");
+
+        let order = order_of(max(count, align));
+        if order > MAX_ORDER {
+            return None;
+        }
+        let start = self.allocate_block(order, zone)?;
+
+        // Give back the frames that were not asked for
+        self.free_range(start + count, start + (1 << order));
+        Some(Frame(start))
+    }
+
+    /// Frees `count` contiguous frames starting at `frame`. Reference counts
+    /// are not checked, so the frames must not be shared.
+    pub fn deallocate_frames(&mut self, frame: Frame, count: usize) {
+        self.free_range(frame.0, frame.0 + count);
+    }
+
+    /// Gives the allocator a frame that it did not manage when it was
+    /// created, such as one that was only used during boot. Frames past the
+    /// end of the bitmaps are skipped. Returns `true` if the frame was taken.
+    pub fn reclaim_frame(&mut self, frame: Frame) -> bool {
+        let held = frame.0 < self.refs_len &&
+            (0..MAX_ORDER + 1).all(|order| (frame.0 >> order) / ENTRY_BITS < self.len[order]);
+        if !held {
+            return false;
+        }
+        self.total[Zone::containing(frame.0) as usize] += 1;
+        self.free_block(frame.0, 0);
+        true
+    }
+
+    /// Adds a reference to `frame`, which must be allocated. The frame is
+    /// then only freed after one more call to `deallocate_frame`.
+    pub fn share_frame(&mut self, frame: &Frame) -> Result<(), &'static str> {
+        let refs = self.refs(frame.0).ok_or("Frame is not managed by the frame allocator")?;
+        *refs = refs.checked_add(1).ok_or("Frame is shared too many times")?;
+        Ok(())
+    }
+
+    /// Returns the number of references to the allocated `frame`
+    pub fn ref_count(&self, frame: &Frame) -> usize {
+        self.refs(frame.0).map_or(1, |refs| *refs as usize + 1)
+    }
+
+    /// Returns the number of free frames
+    pub fn free_frames(&self) -> usize {
+        Zone::all().iter()
+            .map(|&zone| self.free_frames_in(zone))
+            .sum()
+    }
+
+    /// Returns the number of free frames in `zone`
+    pub fn free_frames_in(&self, zone: Zone) -> usize {
+        self.free[zone as usize].iter()
+            .enumerate()
+            .map(|(order, count)| count << order)
+            .sum()
+    }
+
+    /// Returns the number of frames that were given to the allocator in
+    /// `zone`, whether or not they are free.
+    pub fn total_frames_in(&self, zone: Zone) -> usize {
+        self.total[zone as usize]
+    }
+}
+
+impl FrameAllocate for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocate_block(0, Zone::Normal).map(Frame)
+    }
+}
+
+impl FrameDeallocate for BuddyAllocator {
+    /// Drops a reference to `frame`, freeing it if it was the last one.
+    /// Frames past the end of memory, such as device memory, were never
+    /// allocated and must be unmapped with `unmap_frame` instead.
+    fn deallocate_frame(&mut self, frame: Frame) {
+        let shared = match self.refs(frame.0) {
+            Some(refs) if *refs > 0 => {
+                *refs -= 1;
+                true
+            },
+            Some(_) => false,
+            None => {
+                debug_assert!(false, "Frame {:#x} is past the end of memory", frame.0);
+                return;
+            },
+        };
+        if !shared {
+            self.free_block(frame.0, 0);
+        }
+    }
+}
+
+#[cfg(feature = "test")]
+pub mod tests {
+    use memory::{MEMORY_CONTROLLER, Frame, FrameAllocate, FrameDeallocate};
+    use memory::zone::Zone;
+    use tap::TestGroup;
+    use super::BuddyAllocator;
+
+    pub fn run() {
+        let mut lock = MEMORY_CONTROLLER.lock();
+        let frame_allocator = &mut lock.as_mut().unwrap().frame_allocator;
+
+        test_buddy(frame_allocator);
+        test_zones(frame_allocator);
+        test_sharing(frame_allocator);
+    }
+
+    fn test_buddy(frame_allocator: &mut BuddyAllocator) {
+        let mut tap = TestGroup::new(6);
+        tap.diagnostic("Testing the buddy allocator");
+        let free = frame_allocator.free_frames();
+
+        let frame = frame_allocator.allocate_frame();
+        tap.assert_tap(frame.is_some() && frame_allocator.free_frames() == free - 1,
+                       "Could not allocate a single frame");
+        frame_allocator.deallocate_frame(frame.unwrap());
+        tap.assert_tap(frame_allocator.free_frames() == free,
+                       "Freeing a single frame did not restore the free count");
+
+        tap.diagnostic("Testing contiguous allocation");
+        let huge = frame_allocator.allocate_frames(512, 512);
+        tap.assert_tap(huge.as_ref().map_or(false, |frame| frame.0 % 512 == 0),
+                       "Could not allocate 512 frames aligned to 2MiB");
+        let odd = frame_allocator.allocate_frames(3, 1);
+        tap.assert_tap(odd.is_some() && frame_allocator.free_frames() == free - 515,
+                       "Unused frames were not returned after allocating 3 frames");
+
+        frame_allocator.deallocate_frames(huge.unwrap(), 512);
+        frame_allocator.deallocate_frames(odd.unwrap(), 3);
+        tap.assert_tap(frame_allocator.free_frames() == free,
+                       "Freeing contiguous frames did not restore the free count");
+
+        let past_end = Frame(frame_allocator.refs_len);
+        tap.assert_tap(!frame_allocator.reclaim_frame(past_end) &&
+                           frame_allocator.free_frames() == free,
+                       "Frame past the end of the bitmaps was reclaimed");
+    }
+
+    fn test_zones(frame_allocator: &mut BuddyAllocator) {
+        let mut tap = TestGroup::new(4);
+        tap.diagnostic("Testing zones");
+        let free = frame_allocator.free_frames();
+        let dma = frame_allocator.allocate_frame_in(Zone::Dma);
+        tap.assert_tap(dma.as_ref().map_or(false, |frame| Zone::containing(frame.0) == Zone::Dma),
+                       "Could not allocate a frame below 16MiB");
+        let dma32 = frame_allocator.allocate_frames_in(16, 16, Zone::Dma32);
+        tap.assert_tap(dma32.as_ref().map_or(false, |frame| {
+                           Zone::containing(frame.0 + 15) != Zone::Normal
+                       }), "Could not allocate 16 frames below 4GiB");
+        // Without any memory above 4GiB, normal allocations fall back to Dma32
+        let normal = frame_allocator.allocate_frame();
+        tap.assert_tap(normal.as_ref().map_or(false, |frame| {
+                           frame_allocator.total_frames_in(Zone::Normal) != 0
+                               || Zone::containing(frame.0) == Zone::Dma32
+                       }), "Normal allocation did not fall back to the right zone");
+
+        frame_allocator.deallocate_frame(dma.unwrap());
+        frame_allocator.deallocate_frames(dma32.unwrap(), 16);
+        frame_allocator.deallocate_frame(normal.unwrap());
+        tap.assert_tap(frame_allocator.free_frames() == free,
+                       "Freeing zoned frames did not restore the free count");
+    }
+
+    fn test_sharing(frame_allocator: &mut BuddyAllocator) {
+        let mut tap = TestGroup::new(4);
+        tap.diagnostic("Testing shared frames");
+        let free = frame_allocator.free_frames();
+
+        let frame = frame_allocator.allocate_frame().expect("No more frames :(");
+        frame_allocator.share_frame(&frame).expect("Could not share frame");
+        tap.assert_tap(frame_allocator.ref_count(&frame) == 2,
+                       "Shared frame has the wrong reference count");
+
+        frame_allocator.deallocate_frame(frame.clone());
+        tap.assert_tap(frame_allocator.free_frames() == free - 1 &&
+                           frame_allocator.ref_count(&frame) == 1,
+                       "Shared frame was freed while still referenced");
+        frame_allocator.deallocate_frame(frame);
+        tap.assert_tap(frame_allocator.free_frames() == free,
+                       "Shared frame was not freed after its last reference");
+
+        // Such as a device's frame
+        let device = Frame(frame_allocator.refs_len);
+        tap.assert_tap(frame_allocator.share_frame(&device).is_err(),
+                       "Frame past the end of memory was shared");
+    }
+}
@@ -0,0 +1,348 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Page fault resolution
+//!
+//! Regions of kernel memory can be registered as lazily backed. They are not
+//! mapped up front, instead each page is mapped to a zeroed frame the first
+//! time that it is touched.
+//!
+//! Frames can also be shared copy-on-write. Every page that maps a shared
+//! frame is read-only, and the first write to one of them gives that page
+//! its own copy of the frame.
+
+use alloc::boxed::Box;
+use core::ptr;
+
+use sync::IrqLock;
+use memory::{PAGE_SIZE, FrameAllocate, FrameDeallocate};
+use memory::{MemoryController, MEMORY_CONTROLLER};
+use memory::paging::{self, Page, VirtualAddress, EntryFlags};
+
+/// The number of pages below the stack pointer that `prefault_stack` maps
+const CONTROLLER_STACK_PAGES: usize = 4;
+
+bitflags! {
+    /// The error code pushed by the CPU for a page fault
+    pub struct PageFaultError: u64 {
+        /// If set the fault was a protection violation, else the page was not
+        /// present
+        const PROTECTION_VIOLATION = 1 << 0;
+        /// If set the fault was caused by a write, else by a read
+        const CAUSED_BY_WRITE =      1 << 1;
+        /// The fault happened in user mode
+        const USER_MODE =            1 << 2;
+        /// A reserved bit was set in a page table entry
+        const MALFORMED_TABLE =      1 << 3;
+        /// The fault was caused by an instruction fetch
+        const INSTRUCTION_FETCH =    1 << 4;
+    }
+}
+
+/// A range of pages that are mapped on first access
+struct LazyRegion {
+    start: VirtualAddress,
+    end: VirtualAddress,
+    flags: EntryFlags,
+    /// The next region in the list
+    next: Option<Box<LazyRegion>>,
+}
+
+/// Every registered lazy region, as a list. Page faults read it, so it is
+/// never locked for longer than it takes to change a link.
+static LAZY_REGIONS: IrqLock<Option<Box<LazyRegion>>> = IrqLock::new(None);
+
+/// Returns the first region in `list` that overlaps `[start, end)`
+fn find_lazy(list: &Option<Box<LazyRegion>>, start: VirtualAddress, end: VirtualAddress)
+    -> Option<&LazyRegion>
+{
+    let mut next = list.as_ref();
+    while let Some(region) = next {
+        if region.start < end && start < region.end {
+            return Some(&**region);
+        }
+        next = region.next.as_ref();
+    }
+    None
+}
+
+/// Registers the `pages` pages at `start` to be mapped with `flags` when they
+/// are first accessed. None of the pages may already be mapped.
+pub fn register_lazy(start: VirtualAddress, pages: usize, flags: EntryFlags)
+    -> Result<(), &'static str>
+{
+    assert!(start % PAGE_SIZE == 0, "Lazy regions must be page aligned");
+    let mut region = Box::new(LazyRegion {
+        start: start,
+        end: start + pages * PAGE_SIZE,
+        flags: flags,
+        next: None,
+    });
+
+    let mut regions = LAZY_REGIONS.lock();
+    if find_lazy(&regions, region.start, region.end).is_some() {
+        return Err("Lazy region overlaps another lazy region");
+    }
+    region.next = regions.take();
+    *regions = Some(region);
+    Ok(())
+}
+
+/// Removes the lazy region that starts at `start`. Any of its pages that have
+/// been touched stay mapped.
+pub fn unregister_lazy(start: VirtualAddress) -> Result<(), &'static str> {
+    let removed = {
+        let mut regions = LAZY_REGIONS.lock();
+        let mut link = &mut *regions;
+        loop {
+            let found = match *link {
+                Some(ref region) => region.start == start,
+                None => return Err("No lazy region starts at this address"),
+            };
+            if found {
+                break;
+            }
+            let current = link;
+            link = &mut current.as_mut().unwrap().next;
+        }
+        let mut removed = link.take().unwrap();
+        *link = removed.next.take();
+        removed
+    };
+    drop(removed);
+    Ok(())
+}
+
+/// Touches every lazily backed page within `CONTROLLER_STACK_PAGES` pages
+/// below the stack pointer, so that they are mapped.
+///
+/// Lazy faults cannot be resolved while the memory controller is locked, so
+/// this is done before locking it. Code that holds the controller may then
+/// run on a lazily backed stack as long as it stays within those pages.
+pub fn prefault_stack() {
+    let sp: usize;
+    unsafe {
+        asm!("mov $0, rsp" : "=r"(sp) ::: "intel");
+    }
+    for page in 1..CONTROLLER_STACK_PAGES + 1 {
+        let address = sp - page * PAGE_SIZE;
+        let lazy = find_lazy(&LAZY_REGIONS.lock(), address, address + 1).is_some();
+        if lazy {
+            unsafe {
+                ptr::read_volatile(address as *const u8);
+            }
+        }
+    }
+}
+
+/// Maps `target` to the frame of the mapped page `source`, sharing it
+/// copy-on-write. Both pages become read-only until they are written.
+pub fn share_cow(source: Page, target: Page) -> Result<(), &'static str> {
+    let mut lock = MEMORY_CONTROLLER.lock();
+    let &mut MemoryController {
+        ref mut active_table,
+        ref mut frame_allocator,
+        regions: _,
+    } = lock.as_mut().unwrap();
+
+    let frame = active_table.translate_page(source).ok_or("Source page is not mapped")?;
+    let mut flags = active_table.flags(source).ok_or("Source page is not mapped")?;
+    if active_table.translate_page(target).is_some() {
+        return Err("Target page is already mapped");
+    }
+    frame_allocator.share_frame(&frame)?;
+
+    if flags.contains(EntryFlags::WRITABLE) {
+        flags.remove(EntryFlags::WRITABLE);
+        flags.insert(EntryFlags::COPY_ON_WRITE);
+        active_table.remap(source, frame.clone(), flags);
+    }
+    active_table.map_to(target, frame, flags, frame_allocator)
+        .expect("Target page is already mapped");
+    Ok(())
+}
+
+/// Tries to resolve a page fault at `address`. Returns `true` if the faulting
+/// instruction can be resumed.
+pub fn handle_page_fault(address: VirtualAddress, error: PageFaultError) -> bool {
+    if error.contains(PageFaultError::PROTECTION_VIOLATION) {
+        return error.contains(PageFaultError::CAUSED_BY_WRITE) && copy_on_write(address);
+    }
+
+    let flags = match find_lazy(&LAZY_REGIONS.lock(), address, address + 1) {
+        Some(region) => region.flags,
+        None => return false,
+    };
+
+    // Interrupts are disabled while the lock is held, so only the faulting
+    // code can hold it. It has then used more of its stack than
+    // `prefault_stack` mapped, or touched a lazy region, and the fault cannot
+    // be resolved.
+    let mut lock = match MEMORY_CONTROLLER.try_lock() {
+        Some(lock) => lock,
+        None => return false,
+    };
+    let &mut MemoryController {
+        ref mut active_table,
+        ref mut frame_allocator,
+        regions: _,
+    } = lock.as_mut().unwrap();
+
+    let frame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe {
+        ptr::write_bytes(paging::phys_to_virt(frame.start_address()) as *mut u8, 0, PAGE_SIZE);
+    }
+    if active_table.map_to(Page::containing_address(address), frame.clone(),
+                           flags, frame_allocator).is_err() {
+        // Someone else mapped the page first
+        frame_allocator.deallocate_frame(frame);
+    }
+    true
+}
+
+/// Resolves a write to a copy-on-write page. Returns `false` if the page is
+/// not copy-on-write.
+fn copy_on_write(address: VirtualAddress) -> bool {
+    // As for lazy regions, only the faulting code can hold the lock
+    let mut lock = match MEMORY_CONTROLLER.try_lock() {
+        Some(lock) => lock,
+        None => return false,
+    };
+    let &mut MemoryController {
+        ref mut active_table,
+        ref mut frame_allocator,
+        regions: _,
+    } = lock.as_mut().unwrap();
+
+    let page = Page::containing_address(address);
+    let mut flags = match active_table.flags(page) {
+        Some(flags) if flags.contains(EntryFlags::COPY_ON_WRITE) => flags,
+        _ => return false,
+    };
+    flags.remove(EntryFlags::COPY_ON_WRITE);
+    flags.insert(EntryFlags::WRITABLE);
+
+    let frame = active_table.translate_page(page).unwrap();
+    if frame_allocator.ref_count(&frame) == 1 {
+        // Every other page has already made its own copy
+        active_table.remap(page, frame, flags);
+        return true;
+    }
+
+    let copy = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe {
+        ptr::copy_nonoverlapping(paging::phys_to_virt(frame.start_address()) as *const u8,
+                                 paging::phys_to_virt(copy.start_address()) as *mut u8,
+                                 PAGE_SIZE);
+    }
+    let old = active_table.remap(page, copy, flags);
+    frame_allocator.deallocate_frame(old);
+    true
+}
+
+#[cfg(feature = "test")]
+pub mod tests {
+    use tap::TestGroup;
+    use memory::{PAGE_SIZE, MEMORY_CONTROLLER};
+    use memory::vmalloc::{vmalloc, vmalloc_lazy, vfree, Guard};
+    use memory::paging::Page;
+    use super::share_cow;
+
+    fn free_frames() -> usize {
+        MEMORY_CONTROLLER.lock().as_ref().unwrap().frame_allocator.free_frames()
+    }
+
+    fn is_mapped(address: usize) -> bool {
+        MEMORY_CONTROLLER.lock().as_ref().unwrap().active_table.translate(address).is_some()
+    }
+
+    pub fn run() {
+        test_lazy();
+        test_cow();
+    }
+
+    fn test_lazy() {
+        let mut tap = TestGroup::new(4);
+        tap.diagnostic("Testing demand paging");
+
+        let free = free_frames();
+        let size = 16 * 1024 * 1024; // 16MiB
+        let area = vmalloc_lazy(size, Guard::BEFORE | Guard::AFTER)
+            .expect("Could not reserve a lazy area");
+        let start = area.start_address();
+        tap.assert_tap(!is_mapped(start) && !is_mapped(start + size - 1),
+                       "Lazy area was mapped before it was touched");
+
+        let last = (start + size - 8) as *mut u64;
+        let zeroed = unsafe {
+            let zeroed = *last == 0;
+            *last = 0xdead_beef;
+            zeroed && *last == 0xdead_beef
+        };
+        tap.assert_tap(zeroed, "Lazily mapped page was not zeroed and writable");
+        tap.assert_tap(is_mapped(start + size - 1) && !is_mapped(start + size - 1 - PAGE_SIZE),
+                       "Touching a lazy page mapped the wrong pages");
+
+        vfree(area);
+        tap.assert_tap(free_frames() == free, "Lazily mapped frames were not freed");
+    }
+
+    fn test_cow() {
+        let mut tap = TestGroup::new(5);
+        tap.diagnostic("Testing copy-on-write");
+
+        let free = free_frames();
+        let area = vmalloc(PAGE_SIZE, Guard::empty()).expect("Could not vmalloc a page");
+        let source = area.as_mut_ptr() as *mut u64;
+        let target = 0o001_000_000_000_0000 as *mut u64;
+        unsafe {
+            *source = 0x1111;
+        }
+
+        share_cow(Page::containing_address(source as usize),
+                  Page::containing_address(target as usize))
+            .expect("Could not share a page");
+        let translate = |ptr: *mut u64| {
+            MEMORY_CONTROLLER.lock().as_ref().unwrap().active_table.translate(ptr as usize)
+        };
+        tap.assert_tap(unsafe { *target } == 0x1111 && translate(source) == translate(target),
+                       "Shared page does not map the same frame");
+
+        unsafe {
+            *target = 0x2222;
+        }
+        tap.assert_tap(unsafe { *source == 0x1111 && *target == 0x2222 },
+                       "Writing a shared page did not copy it");
+        tap.assert_tap(translate(source) != translate(target),
+                       "Written shared page still maps the same frame");
+
+        // The source is the last reference, so it can be written in place
+        let frame = translate(source);
+        unsafe {
+            *source = 0x3333;
+        }
+        tap.assert_tap(unsafe { *source } == 0x3333 && translate(source) == frame,
+                       "Last reference to a shared frame was copied");
+
+        {
+            let mut lock = MEMORY_CONTROLLER.lock();
+            let controller = lock.as_mut().unwrap();
+            controller.active_table.unmap(Page::containing_address(target as usize),
+                                          &mut controller.frame_allocator);
+        }
+        vfree(area);
+        tap.assert_tap(free_frames() == free, "Shared frames were leaked");
+    }
+}
@@ -9,23 +9,50 @@
 
 #![allow(dead_code,unused_variables)]
 
-use spin::Mutex;
-
 use multiboot2::BootInformation;
 
-pub use self::stack_allocator::Stack;
+use spin::Mutex;
+
+use sync::{IrqMutex, IrqMutexGuard};
+
+pub use self::stack_allocator::{Stack, StackError, MAX_STACK_PAGES};
+pub use self::zone::Zone;
+pub use self::slab::{ObjectCache, SlabBox};
+pub use self::paging::{phys_to_virt, virt_to_phys};
+pub use self::vmalloc::{vmalloc, vmalloc_lazy, vfree, VmArea, Guard};
+pub use self::fault::{register_lazy, unregister_lazy, share_cow, handle_page_fault};
+pub use self::fault::PageFaultError;
+pub use self::paging::EntryFlags;
+pub use self::paging::{AddressSpace, MappedRegion, switch_address_space};
+pub use self::paging::{ioremap, iounmap, CacheMode, IoMapping, IoremapError};
+pub use self::paging::{walk, print_mappings, Walk, WalkStep, Mapping};
+pub use self::paging::audit;
+pub use self::stats::{stats, print_stats, write_oom_report, MemInfo};
 
 use self::area_frame_allocator::AreaFrameAllocator;
-use self::frame_bitmap::FrameBitmap;
-use self::paging::PhysicalAddress;
-use self::paging::ActivePageTable;
+use self::buddy_allocator::BuddyAllocator;
+use self::region_allocator::RegionAllocator;
+use self::paging::{PhysicalAddress, VirtualAddress};
+use self::paging::{ActivePageTable, Page};
 
 /// Allocator for stacks
 mod stack_allocator;
+/// Allocator for ranges of kernel virtual memory.
+mod region_allocator;
+/// Virtually contiguous allocations.
+mod vmalloc;
+/// Page fault resolution.
+mod fault;
+/// Memory usage statistics.
+mod stats;
 /// Allocator for physical frames.
 mod area_frame_allocator;
-/// Physical frame allocator that uses a bitmap.
-mod frame_bitmap;
+/// Physical frame allocator that uses the buddy system.
+mod buddy_allocator;
+/// Ranges of physical memory for restricted devices.
+mod zone;
+/// Caches for fixed size kernel objects.
+mod slab;
 /// Virtual paging module.
 mod paging;
 
@@ -34,35 +61,197 @@ pub const KERNEL_BASE: usize = 0xFFFF_FFFF_8000_0000;
 /// The size of a single page (or physical frame)
 pub const PAGE_SIZE: usize = 4096;
 
-// TODO Replace this with a dynamic heap
+/// The start of the kernel virtual memory that is handed out in regions
+const REGION_START: usize = 0o177777_600_000_000_000_0000;
+/// The number of pages that are handed out in regions (512GiB)
+const REGION_PAGES: usize = 512 * 262144;
+
 /// The begining of the kernel heap
-const HEAP_START: usize = 0o000_001_000_0000;
-/// The size of the kernel heap
+const HEAP_START: usize = REGION_START;
+/// The size of the kernel heap when it is first mapped
 const HEAP_SIZE: usize = 25 * PAGE_SIZE;
+/// The largest size that the kernel heap may grow to (64MiB)
+const HEAP_MAX_SIZE: usize = 16384 * PAGE_SIZE;
+/// If set, free pages at the top of the heap are returned to the frame
+/// allocator
+const HEAP_SHRINK: bool = true;
 
 /// A struct that gives access to the physical and virtual memory managers.
 struct MemoryController {
     active_table:ActivePageTable,
-    frame_allocator: FrameBitmap,
-    stack_allocator: stack_allocator::StackAllocator,
+    frame_allocator: BuddyAllocator,
+    regions: RegionAllocator,
 }
 
 /// A static `MemoryController`. Will always be Some(_) after init completes.
-static MEMORY_CONTROLLER: Mutex<Option<MemoryController>> = Mutex::new(None);
+///
+/// Interrupts are disabled while it is locked, so a page fault handler can
+/// only find it locked if the fault came from code holding the lock. The heap
+/// can still be used while it is held, it then grows into `HEAP_RESERVE`.
+static MEMORY_CONTROLLER: ControllerLock = ControllerLock(IrqMutex::new(None));
+
+/// The lock around the `MemoryController`.
+///
+/// Lazy faults cannot be resolved while the controller is locked, so locking
+/// it first maps the part of the current stack that may be used until it is
+/// unlocked.
+struct ControllerLock(IrqMutex<Option<MemoryController>>);
+
+impl ControllerLock {
+    fn lock(&self) -> IrqMutexGuard<Option<MemoryController>> {
+        fault::prefault_stack();
+        self.0.lock()
+    }
+
+    fn try_lock(&self) -> Option<IrqMutexGuard<Option<MemoryController>>> {
+        fault::prefault_stack();
+        self.0.try_lock()
+    }
+}
+
+/// The number of frames kept aside for the heap to grow into while the memory
+/// controller is locked
+const HEAP_RESERVE_FRAMES: usize = 32;
+
+/// Frames that the heap grows into, so that growing it never needs the memory
+/// controller. It is refilled whenever the heap grows or shrinks while the
+/// controller is free.
+static HEAP_RESERVE: Mutex<FrameReserve> = Mutex::new(FrameReserve::new());
 
+/// A small stack of frames that were taken from the frame allocator
+struct FrameReserve {
+    frames: [usize; HEAP_RESERVE_FRAMES],
+    len: usize,
+}
+
+impl FrameReserve {
+    const fn new() -> FrameReserve {
+        FrameReserve {
+            frames: [0; HEAP_RESERVE_FRAMES],
+            len: 0,
+        }
+    }
+
+    /// Takes frames from `allocator` until the reserve is full or `allocator`
+    /// runs out
+    fn fill<A>(&mut self, allocator: &mut A)
+        where A: FrameAllocate
+    {
+        while self.len < HEAP_RESERVE_FRAMES {
+            match allocator.allocate_frame() {
+                Some(frame) => {
+                    self.frames[self.len] = frame.0;
+                    self.len += 1;
+                },
+                None => break,
+            }
+        }
+    }
+}
+
+impl FrameAllocate for FrameReserve {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(Frame(self.frames[self.len]))
+    }
+}
 
 /// Allocates a stack of `size` pages
-pub fn alloc_stack(size: usize) -> Result<Stack, &'static str> {
+pub fn alloc_stack(size: usize) -> Result<Stack, StackError> {
     let mut lock = MEMORY_CONTROLLER.lock();
     let &mut MemoryController {
         ref mut active_table,
         ref mut frame_allocator,
-        ref mut stack_allocator,
+        ref mut regions,
     } = lock.as_mut().unwrap();
 
-    stack_allocator.alloc_stack(active_table,
-                                frame_allocator,
-                                size)
+    stack_allocator::alloc_stack(active_table,
+                                 frame_allocator,
+                                 regions,
+                                 size)
+}
+
+/// Allocates `count` physically contiguous frames, the first of which is
+/// aligned to `align` frames. `align` must be a power of two.
+pub fn allocate_frames(count: usize, align: usize) -> Option<Frame> {
+    let mut lock = MEMORY_CONTROLLER.lock();
+    lock.as_mut().unwrap().frame_allocator.allocate_frames(count, align)
+}
+
+/// Allocates `count` physically contiguous frames from `zone`, falling back to
+/// lower zones if `zone` is exhausted. The first frame is aligned to `align`
+/// frames, which must be a power of two.
+pub fn allocate_frames_in(count: usize, align: usize, zone: Zone) -> Option<Frame> {
+    let mut lock = MEMORY_CONTROLLER.lock();
+    lock.as_mut().unwrap().frame_allocator.allocate_frames_in(count, align, zone)
+}
+
+/// Frees `count` contiguous frames starting at `frame`
+pub fn deallocate_frames(frame: Frame, count: usize) {
+    let mut lock = MEMORY_CONTROLLER.lock();
+    lock.as_mut().unwrap().frame_allocator.deallocate_frames(frame, count)
+}
+
+/// Maps up to `pages` pages to the top of the heap at `top`, stopping at
+/// `HEAP_MAX_SIZE` or when out of frames. Returns the number of pages mapped.
+///
+/// The pages and any page tables they need are taken from `HEAP_RESERVE`,
+/// which is refilled first if the memory controller is free. If it is locked
+/// the heap is being used by the code holding it, and the reserve is all
+/// there is.
+fn grow_heap(top: VirtualAddress, pages: usize) -> usize {
+    let mut reserve = HEAP_RESERVE.lock();
+    let mut lock = MEMORY_CONTROLLER.try_lock();
+    // Interrupts are disabled while the controller is locked, so whoever holds
+    // it is not in the middle of changing the page tables. The heap's own
+    // tables are only changed with the heap locked.
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    let start_page = Page::containing_address(top);
+    let mut mapped = 0;
+    for page in Page::range_inclusive(start_page, start_page + (pages - 1)) {
+        if page.start_address() >= HEAP_START + HEAP_MAX_SIZE {
+            break;
+        }
+        if let Some(ref mut lock) = lock {
+            reserve.fill(&mut lock.as_mut().unwrap().frame_allocator);
+        }
+        // The page may need a new p1 table as well
+        if reserve.len < 2 {
+            break;
+        }
+        let frame = reserve.allocate_frame().unwrap();
+        active_table.map_to(page, frame, paging::EntryFlags::WRITABLE |
+                            paging::EntryFlags::NO_EXECUTE, &mut *reserve)
+            .expect("Heap page is already mapped");
+        mapped += 1;
+    }
+    mapped
+}
+
+/// Unmaps `pages` pages from the top of the heap, starting at `top`. Returns
+/// `false` if the memory controller is locked, the pages are kept then.
+fn shrink_heap(top: VirtualAddress, pages: usize) -> bool {
+    let mut reserve = HEAP_RESERVE.lock();
+    let mut lock = match MEMORY_CONTROLLER.try_lock() {
+        Some(lock) => lock,
+        None => return false,
+    };
+    let &mut MemoryController {
+        ref mut active_table,
+        ref mut frame_allocator,
+        regions: _,
+    } = lock.as_mut().unwrap();
+
+    let start_page = Page::containing_address(top);
+    for page in Page::range_inclusive(start_page, start_page + (pages - 1)) {
+        active_table.unmap(page, frame_allocator);
+    }
+    reserve.fill(frame_allocator);
+    true
 }
 
 /// Initializes memory to a defined state.
@@ -79,10 +268,15 @@ pub fn init(boot_info: &BootInformation) {
     let elf_sections_tag = boot_info.elf_sections_tag()
         .expect("ELF sections tag required");
 
+    // The init section is linked at its physical address. It is reserved
+    // until `reclaim_boot_memory`.
     let kernel_start = elf_sections_tag.sections()
         .filter(|s| s.is_allocated())
-        .filter(|s| s.start_address() >= KERNEL_BASE)
-        .map(|s| s.start_address() - KERNEL_BASE)
+        .map(|s| if s.start_address() >= KERNEL_BASE {
+            s.start_address() - KERNEL_BASE
+        } else {
+            s.start_address()
+        })
         .min()
         .unwrap();
     let kernel_end = elf_sections_tag.sections()
@@ -104,6 +298,10 @@ pub fn init(boot_info: &BootInformation) {
 
     let mut active_table = unsafe {paging::ActivePageTable::new()};
 
+    // Program the PAT before anything can be mapped with it
+    paging::init_pat();
+    paging::init_protection();
+
     let frame_allocator =
         AreaFrameAllocator::new(kernel_start as usize,
                                 kernel_end as usize,
@@ -112,33 +310,82 @@ pub fn init(boot_info: &BootInformation) {
                                 boot_info,
                                 memory_map_tag.memory_areas());
 
-    let frame_bitmap =
+    let mut buddy_allocator =
         paging::remap_the_kernel(&mut active_table, frame_allocator, boot_info);
 
-    let stack_allocator = {
-        let alloc_start = heap_end_page + 1;
-        let alloc_end = alloc_start + 100;
-        let alloc_range = Page::range_inclusive(alloc_start, alloc_end);
-
-        stack_allocator::StackAllocator::new(alloc_range)
-    };
-
-    *MEMORY_CONTROLLER.lock() = Some(MemoryController {
-        active_table: active_table,
-        frame_allocator: frame_bitmap,
-        stack_allocator: stack_allocator,
-    });
+    for &zone in Zone::all().iter() {
+        println!("Zone {:?}: {} frames", zone, buddy_allocator.total_frames_in(zone));
+    }
 
-    use self::paging::Page;
     use hole_list_allocator;
 
     for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        page.map(paging::EntryFlags::WRITABLE);
+        active_table.map(page, paging::EntryFlags::WRITABLE | paging::EntryFlags::NO_EXECUTE,
+                         &mut buddy_allocator);
     }
 
     unsafe {
         hole_list_allocator::init(HEAP_START, HEAP_SIZE);
     }
+
+    // The region allocator keeps its free ranges on the heap
+    let mut regions = RegionAllocator::new(REGION_START, REGION_PAGES);
+    // Leave room for the heap to grow
+    regions.reserve(HEAP_START, HEAP_MAX_SIZE / PAGE_SIZE)
+        .expect("Could not reserve the heap");
+
+    HEAP_RESERVE.lock().fill(&mut buddy_allocator);
+    *MEMORY_CONTROLLER.lock() = Some(MemoryController {
+        active_table: active_table,
+        frame_allocator: buddy_allocator,
+        regions: regions,
+    });
+
+    hole_list_allocator::set_backing(hole_list_allocator::Backing {
+        grow: grow_heap,
+        shrink: if HEAP_SHRINK { Some(shrink_heap) } else { None },
+        page_size: PAGE_SIZE,
+    });
+}
+
+/// Gives the memory that is only used during boot back to the frame
+/// allocator. Returns the number of bytes reclaimed.
+///
+/// This must be called once, at the end of initialization.
+pub fn reclaim_boot_memory(boot_info: BootInformation) -> usize {
+    assert_has_not_been_called!("Boot memory can only be reclaimed once");
+
+    let frames = paging::boot_frames(&boot_info);
+    // The information structure is unmapped
+    drop(boot_info);
+
+    let mut lock = MEMORY_CONTROLLER.lock();
+    let &mut MemoryController {
+        ref mut active_table,
+        ref mut frame_allocator,
+        regions: _,
+    } = lock.as_mut().unwrap();
+
+    paging::reclaim_boot_memory(active_table, frame_allocator, &frames)
+}
+
+/// Prints every live heap allocation and the return addresses of its callers
+/// over serial
+#[cfg(feature = "heap-debug")]
+pub fn heap_leak_report() {
+    use hole_list_allocator;
+
+    serial_println!("# Live heap allocations:");
+    let mut count = 0;
+    let mut bytes = 0;
+    let untracked = hole_list_allocator::for_each_allocation(|allocation| {
+        serial_println!("# {:#x}: {} bytes from {:x?}",
+                        allocation.address, allocation.size, allocation.callers);
+        count += 1;
+        bytes += allocation.size;
+    });
+    serial_println!("# {} allocations of {} bytes, {} more were not recorded",
+                    count, bytes, untracked);
 }
 
 /// A representation of a physical frame.
@@ -152,7 +399,7 @@ impl Frame {
     }
 
     /// Returns the first address in the `Frame`
-    fn start_address(&self) -> PhysicalAddress {
+    pub fn start_address(&self) -> PhysicalAddress {
         self.0 * PAGE_SIZE
     }
 
@@ -206,6 +453,19 @@ pub mod tests {
     pub fn run() {
         // run the tests
         test_memory_alloc();
+        test_heap_growth();
+        test_heap_reserve();
+        test_stack_reuse();
+        test_lazy_stack();
+        test_lazy_stack_locked();
+        #[cfg(feature = "heap-debug")]
+        test_heap_debug();
+        super::buddy_allocator::tests::run();
+        super::region_allocator::tests::run();
+        super::vmalloc::tests::run();
+        super::fault::tests::run();
+        super::stats::tests::run();
+        super::slab::tests::run();
         super::paging::tests::run();
     }
 
@@ -217,4 +477,170 @@ pub mod tests {
         let heap_test = Box::new(42);
         tap.assert_tap(*heap_test == 42, "Could not access Box");
     }
+
+    fn test_heap_growth() {
+        use alloc::vec::Vec;
+        use hole_list_allocator;
+
+        let mut tap = TestGroup::new(3);
+        tap.diagnostic("Testing heap growth");
+        let size = 4 * 1024 * 1024; // 4MiB
+        let heap_size = hole_list_allocator::size();
+        {
+            let mut big: Vec<u8> = Vec::with_capacity(size);
+            big.resize(size, 0xa5);
+            tap.assert_tap(big[0] == 0xa5 && big[size - 1] == 0xa5,
+                           "Could not access a 4MiB allocation");
+            tap.assert_tap(hole_list_allocator::size() > heap_size,
+                           "Heap did not grow to fit a 4MiB allocation");
+        }
+        tap.assert_tap(!super::HEAP_SHRINK || hole_list_allocator::size() <= heap_size,
+                       "Heap did not shrink after freeing a 4MiB allocation");
+    }
+
+    fn test_heap_reserve() {
+        use alloc::vec::Vec;
+        use hole_list_allocator;
+        use super::{MEMORY_CONTROLLER, HEAP_RESERVE, HEAP_RESERVE_FRAMES};
+
+        let mut tap = TestGroup::new(2);
+        tap.diagnostic("Testing heap growth with the memory controller locked");
+
+        let grew = {
+            let _lock = MEMORY_CONTROLLER.lock();
+            let heap_size = hole_list_allocator::size();
+            // An allocation the size of the whole heap never fits without
+            // growing it, even if the reserve runs out before it does
+            let mut big: Vec<u8> = Vec::new();
+            let _ = big.try_reserve(heap_size);
+            hole_list_allocator::size() > heap_size
+        };
+        tap.assert_tap(grew, "Heap did not grow with the memory controller locked");
+
+        // Growing and shrinking with the controller free refills the reserve
+        drop(Vec::<u8>::with_capacity(hole_list_allocator::size()));
+        tap.assert_tap(HEAP_RESERVE.lock().len == HEAP_RESERVE_FRAMES,
+                       "Heap reserve was not refilled");
+    }
+
+    #[cfg(feature = "heap-debug")]
+    fn test_heap_debug() {
+        use alloc::boxed::Box;
+        use hole_list_allocator::{self, CANARY};
+
+        let mut tap = TestGroup::new(3);
+        tap.diagnostic("Testing heap debugging");
+
+        let is_live = |address: usize| {
+            let mut live = false;
+            hole_list_allocator::for_each_allocation(|a| live |= a.address == address);
+            live
+        };
+
+        let boxed = Box::new([0u8; 24]);
+        let address = &*boxed as *const _ as usize;
+        tap.assert_tap(is_live(address), "Allocation was not recorded");
+        tap.assert_tap(unsafe { *((address + 24) as *const u8) } == CANARY &&
+                           unsafe { *((address - 1) as *const u8) } == CANARY,
+                       "Allocation is not surrounded by red zones");
+
+        drop(boxed);
+        tap.assert_tap(!is_live(address), "Freed allocation is still recorded");
+    }
+
+    fn test_stack_reuse() {
+        use super::{alloc_stack, MEMORY_CONTROLLER, PAGE_SIZE};
+
+        let mut tap = TestGroup::new(2);
+        tap.diagnostic("Testing stack reuse");
+
+        let stack = alloc_stack(4).expect("Could not allocate a stack");
+        let bottom = stack.bottom();
+        drop(stack);
+
+        let stack = alloc_stack(4).expect("Could not allocate a stack");
+        tap.assert_tap(stack.bottom() == bottom, "Freed stack region was not reused");
+
+        let guarded = {
+            let lock = MEMORY_CONTROLLER.lock();
+            let active_table = &lock.as_ref().unwrap().active_table;
+            active_table.translate(stack.bottom() - PAGE_SIZE).is_none() &&
+                active_table.translate(stack.bottom()).is_some()
+        };
+        tap.assert_tap(guarded, "Stack does not have a guard page");
+    }
+
+    fn test_lazy_stack() {
+        use core::ptr;
+        use super::{alloc_stack, MEMORY_CONTROLLER};
+        use super::stack_allocator::EAGER_STACK_PAGES;
+
+        let mut tap = TestGroup::new(3);
+        tap.diagnostic("Testing lazily backed stacks");
+
+        let free_frames = || {
+            MEMORY_CONTROLLER.lock().as_ref().unwrap().frame_allocator.free_frames()
+        };
+        let is_mapped = |address| {
+            MEMORY_CONTROLLER.lock().as_ref().unwrap().active_table.translate(address).is_some()
+        };
+
+        let free = free_frames();
+        let stack = alloc_stack(EAGER_STACK_PAGES + 4).expect("Could not allocate a stack");
+        tap.assert_tap(is_mapped(stack.mapped_bottom()) && !is_mapped(stack.bottom()),
+                       "Bottom of a large stack was mapped when it was allocated");
+
+        let bottom = stack.bottom() as *mut u64;
+        let written = unsafe {
+            ptr::write_volatile(bottom, 0xcafe);
+            ptr::read_volatile(bottom) == 0xcafe
+        };
+        tap.assert_tap(written && is_mapped(stack.bottom()),
+                       "Touching the bottom of a large stack did not map it");
+
+        drop(stack);
+        tap.assert_tap(free_frames() == free, "Lazily backed stack leaked frames");
+    }
+
+    fn test_lazy_stack_locked() {
+        use scheduler::Builder;
+        use super::PAGE_SIZE;
+        use super::stack_allocator::EAGER_STACK_PAGES;
+
+        let mut tap = TestGroup::new(1);
+        tap.diagnostic("Testing lazily backed stacks with the memory controller locked");
+
+        let status = Builder::new()
+            .stack_pages(EAGER_STACK_PAGES + 8)
+            .spawn(below_eager_pages)
+            .map(|handle| handle.join());
+        tap.assert_tap(status == Ok(2 * PAGE_SIZE),
+                       "Lazily backed stack was not usable with the memory controller locked");
+    }
+
+    /// Moves the stack pointer below the eagerly mapped pages, then locks the
+    /// memory controller and uses lazily backed pages that were never touched
+    #[inline(never)]
+    fn below_eager_pages() -> usize {
+        use core::ptr;
+        use super::{MEMORY_CONTROLLER, PAGE_SIZE};
+        use super::stack_allocator::EAGER_STACK_PAGES;
+
+        let mut buffer = [0u8; EAGER_STACK_PAGES * PAGE_SIZE];
+        unsafe { ptr::write_volatile(&mut buffer[0], 1) };
+        let _lock = MEMORY_CONTROLLER.lock();
+        use_two_pages()
+    }
+
+    #[inline(never)]
+    fn use_two_pages() -> usize {
+        use core::ptr;
+        use super::PAGE_SIZE;
+
+        let mut buffer = [0u8; 2 * PAGE_SIZE];
+        for byte in buffer.iter_mut() {
+            unsafe { ptr::write_volatile(byte, 1) };
+        }
+        buffer.iter().map(|&b| b as usize).sum()
+    }
 }
@@ -0,0 +1,325 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Separate address spaces
+//!
+//! Every address space shares the kernel's P4 entries, so kernel memory is
+//! mapped the same way in all of them. The rest of the lower half is private
+//! to each address space and is torn down with it.
+//!
+//! Each thread runs in its own address space, or the kernel's, which the
+//! scheduler switches to when it switches threads. The active address space
+//! is kept alive until another one is switched to, so it is never dropped.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::Ordering;
+
+use x86_64::registers::control_regs;
+
+use memory::{PAGE_SIZE, Frame, FrameAllocate, FrameDeallocate};
+use memory::{MemoryController, MEMORY_CONTROLLER};
+use memory::buddy_allocator::BuddyAllocator;
+use sync::IrqLock;
+use super::{Page, VirtualAddress, EntryFlags, InactivePageTable};
+use super::{ENTRY_COUNT, KERNEL_P4, TABLE_FRAMES, physmap_table};
+
+/// The first P4 entry that is private to each address space. The first entry
+/// holds the VGA buffer, so it is shared.
+const PRIVATE_START: usize = 1;
+/// The P4 entry after the last private one. The upper half holds the rest of
+/// the kernel.
+const PRIVATE_END: usize = 256;
+
+/// The active address space, or `None` if it is the kernel's
+// FIXME make CPU local
+static ACTIVE: IrqLock<Option<Arc<AddressSpace>>> = IrqLock::new(None);
+
+/// A range of pages mapped in an `AddressSpace`
+#[derive(Debug, Clone, Copy)]
+pub struct MappedRegion {
+    start: VirtualAddress,
+    pages: usize,
+    flags: EntryFlags,
+}
+
+impl MappedRegion {
+    /// Returns the first address in the region
+    pub fn start_address(&self) -> VirtualAddress {
+        self.start
+    }
+
+    /// Returns the address just past the end of the region
+    pub fn end_address(&self) -> VirtualAddress {
+        self.start + self.pages * PAGE_SIZE
+    }
+
+    /// Returns the flags the region is mapped with
+    pub fn flags(&self) -> EntryFlags {
+        self.flags
+    }
+}
+
+/// A set of page tables that shares the kernel with every other address space
+pub struct AddressSpace {
+    p4_frame: Frame,
+    regions: Vec<MappedRegion>,
+}
+
+impl AddressSpace {
+    /// Creates an address space that only maps the kernel
+    pub fn new() -> Result<AddressSpace, &'static str> {
+        let mut lock = MEMORY_CONTROLLER.lock();
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            regions: _,
+        } = lock.as_mut().unwrap();
+
+        let frame = frame_allocator.allocate_frame()
+            .ok_or("Could not allocate a P4 table")?;
+        let table = InactivePageTable::new(frame, active_table);
+
+        // Share every kernel entry that is not already set up. They never
+        // change, because every P3 table of the upper half exists from boot.
+        let p4 = physmap_table(table.p4_frame.clone());
+        for index in (0..ENTRY_COUNT).filter(|&i| !is_private(i) && i != 510) {
+            let entry = &active_table.p4()[index];
+            if let Some(frame) = entry.pointed_frame() {
+                p4[index].set(frame, entry.flags());
+            }
+        }
+
+        Ok(AddressSpace {
+            p4_frame: table.p4_frame,
+            regions: Vec::new(),
+        })
+    }
+
+    /// Maps `pages` pages starting at `start` to new frames with `flags`. The
+    /// region must be in the private part of the address space and must not
+    /// overlap another region.
+    pub fn map_region(&mut self, start: VirtualAddress, pages: usize, flags: EntryFlags)
+        -> Result<(), &'static str>
+    {
+        let region = MappedRegion {
+            start: start,
+            pages: pages,
+            flags: flags,
+        };
+        if start % PAGE_SIZE != 0 || pages == 0 {
+            return Err("Region is not page aligned or is empty");
+        }
+        let first = Page::containing_address(start).p4_index();
+        let last = Page::containing_address(region.end_address() - 1).p4_index();
+        if !is_private(first) || !is_private(last) {
+            return Err("Region is outside of the private address space");
+        }
+        if self.regions.iter().any(|r| r.start < region.end_address() && start < r.end_address()) {
+            return Err("Region overlaps another region");
+        }
+
+        let mut lock = MEMORY_CONTROLLER.lock();
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            regions: _,
+        } = lock.as_mut().unwrap();
+
+        let mut result = Ok(());
+        active_table.with(&mut self.inactive_table(), |mapper| {
+            let start_page = Page::containing_address(start);
+            for i in 0..pages {
+                let frame = match frame_allocator.allocate_frame() {
+                    Some(frame) => frame,
+                    None => {
+                        // Undo everything that has been mapped so far
+                        for page in (0..i).map(|j| start_page + j) {
+                            mapper.unmap(page, frame_allocator);
+                        }
+                        result = Err("Out of frames");
+                        return;
+                    },
+                };
+                mapper.map_to(start_page + i, frame, flags, frame_allocator)
+                    .expect("Region is already mapped");
+            }
+        });
+
+        if result.is_ok() {
+            self.regions.push(region);
+        }
+        result
+    }
+
+    /// Unmaps the region that starts at `start` and frees its frames
+    pub fn unmap_region(&mut self, start: VirtualAddress) -> Result<(), &'static str> {
+        let index = self.regions.iter().position(|r| r.start == start)
+            .ok_or("No region starts at this address")?;
+        let region = self.regions.remove(index);
+
+        let mut lock = MEMORY_CONTROLLER.lock();
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            regions: _,
+        } = lock.as_mut().unwrap();
+
+        active_table.with(&mut self.inactive_table(), |mapper| {
+            let start_page = Page::containing_address(region.start);
+            for page in Page::range_inclusive(start_page, start_page + (region.pages - 1)) {
+                mapper.unmap(page, frame_allocator);
+            }
+        });
+        Ok(())
+    }
+
+    /// Returns every region mapped in the address space
+    pub fn regions(&self) -> &[MappedRegion] {
+        &self.regions
+    }
+
+    /// Returns true if this is the active address space
+    pub fn is_active(&self) -> bool {
+        Frame::containing_address(control_regs::cr3().0 as usize) == self.p4_frame
+    }
+
+    /// Returns an `InactivePageTable` for the P4 table. It must not outlive
+    /// the address space.
+    fn inactive_table(&self) -> InactivePageTable {
+        InactivePageTable { p4_frame: self.p4_frame.clone() }
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees every frame mapped in the private part of the address space and
+    /// all of its page tables
+    fn drop(&mut self) {
+        let mut lock = MEMORY_CONTROLLER.lock();
+        let frame_allocator = &mut lock.as_mut().unwrap().frame_allocator;
+
+        let p4 = physmap_table(self.p4_frame.clone());
+        for index in PRIVATE_START..PRIVATE_END {
+            if let Some(p3) = p4[index].pointed_frame() {
+                free_table(p3, 3, frame_allocator);
+            }
+        }
+        frame_allocator.deallocate_frame(self.p4_frame.clone());
+        TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Makes `space` the active address space, or the kernel's if it is `None`.
+/// Nothing is done if it is already active. `space` is kept alive for as long
+/// as it is active.
+///
+/// This is used when switching threads, so it does not lock the memory
+/// controller. The active table follows the switch through the recursive
+/// mapping, which every address space has.
+pub fn switch_address_space(space: Option<&Arc<AddressSpace>>) {
+    use x86_64::PhysicalAddress;
+
+    let frame = match space {
+        Some(space) => space.p4_frame.clone(),
+        None => Frame(KERNEL_P4.load(Ordering::Relaxed)),
+    };
+    let previous = {
+        let mut active = ACTIVE.lock();
+        if Frame::containing_address(control_regs::cr3().0 as usize) != frame {
+            unsafe {
+                control_regs::cr3_write(PhysicalAddress(frame.start_address() as u64));
+            }
+        }
+        mem::replace(&mut *active, space.cloned())
+    };
+    // The thread that ran in the previous space still owns it when threads
+    // are switched, so this only drops it if it was switched to directly
+    drop(previous);
+}
+
+/// Returns true if the P4 entry at `index` is private to each address space
+fn is_private(index: usize) -> bool {
+    PRIVATE_START <= index && index < PRIVATE_END
+}
+
+/// Frees the table in `frame` at `level` (1 for a P1 table), every table
+/// below it and every frame that they map.
+fn free_table(frame: Frame, level: usize, allocator: &mut BuddyAllocator) {
+    let table = physmap_table(frame.clone());
+    for index in 0..ENTRY_COUNT {
+        let entry = &table[index];
+        let next = match entry.pointed_frame() {
+            Some(next) => next,
+            None => continue,
+        };
+        if level == 1 {
+            allocator.deallocate_frame(next);
+        } else if !entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            free_table(next, level - 1, allocator);
+        }
+        // Huge pages are never mapped by an address space, so they are not
+        // owned by it
+    }
+    allocator.deallocate_frame(frame);
+    TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+}
+
+#[cfg(feature = "test")]
+pub mod tests {
+    use alloc::sync::Arc;
+    use core::ptr;
+    use tap::TestGroup;
+    use memory::MEMORY_CONTROLLER;
+    use memory::EntryFlags;
+    use scheduler::Builder;
+    use super::AddressSpace;
+
+    fn free_frames() -> usize {
+        MEMORY_CONTROLLER.lock().as_ref().unwrap().frame_allocator.free_frames()
+    }
+
+    fn is_mapped(address: usize) -> bool {
+        MEMORY_CONTROLLER.lock().as_ref().unwrap().active_table.translate(address).is_some()
+    }
+
+    pub fn run() {
+        let mut tap = TestGroup::new(5);
+        tap.diagnostic("Testing address spaces");
+
+        let free = free_frames();
+        let address = 0o001_000_000_000_0000;
+        let mut space = AddressSpace::new().expect("Could not create an address space");
+        space.map_region(address, 4, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE)
+            .expect("Could not map a region");
+        tap.assert_tap(space.regions().len() == 1 && !is_mapped(address),
+                       "Region was mapped in the active address space");
+        tap.assert_tap(space.map_region(address + 0x1000, 1, EntryFlags::WRITABLE).is_err(),
+                       "Overlapping region was mapped");
+
+        let space = Arc::new(space);
+        let status = Builder::new()
+            .address_space(space.clone())
+            .spawn(move || {
+                let ptr = address as *mut u64;
+                unsafe {
+                    ptr::write_volatile(ptr, 0xcafe);
+                    ptr::read_volatile(ptr) as usize
+                }
+            })
+            .map(|handle| handle.join());
+        tap.assert_tap(status == Ok(0xcafe), "Could not use a region in its address space");
+        tap.assert_tap(!space.is_active() && !is_mapped(address),
+                       "Address space was not switched with its thread");
+
+        // The thread was freed when it was joined
+        drop(Arc::try_unwrap(space).ok().expect("Thread did not drop its address space"));
+        tap.assert_tap(free_frames() == free, "Address space leaked frames");
+    }
+}
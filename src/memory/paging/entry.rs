@@ -67,9 +67,15 @@ bitflags! {
         const DIRTY =           1 << 6;
         /// If set, pages are 4MiB large. Otherwise they are 4KiB large
         const HUGE_PAGE =       1 << 7;
+        /// In a P1 entry, selects an entry in the upper half of the PAT. This
+        /// is the same bit as `HUGE_PAGE` in the other tables.
+        const PAT =             1 << 7;
         /// Prevent the CPU from updating this page on a CR3 change.
         /// (use for kernel pages)
         const GLOBAL =          1 << 8;
+        /// Software bit: the frame is shared and is copied on the first
+        /// write. The page is mapped read-only while this is set.
+        const COPY_ON_WRITE =   1 << 9;
         /// If set, fault on execution of the page
         const NO_EXECUTE =      1 << 63;
     }
@@ -0,0 +1,223 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Mapping device memory
+//!
+//! The page attribute table is programmed so that the cache disable and
+//! write-through bits keep their default meaning, and the `PAT` bit selects
+//! write-combining.
+
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use x86_64::instructions::{wrmsr, tlb};
+
+use cpuio::cpuid;
+use memory::{PAGE_SIZE, Frame};
+use memory::{MemoryController, MEMORY_CONTROLLER};
+use memory::region_allocator::Region;
+use super::{Page, PhysicalAddress, VirtualAddress, EntryFlags, PHYSMAP_BASE};
+
+/// The page attribute table MSR
+const IA32_PAT: u32 = 0x277;
+/// WB, WT, UC-, UC, WC, WT, UC-, UC from the lowest entry up
+const PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+
+/// Set once the PAT has been programmed
+static PAT_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// How the CPU caches a mapping of device memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Every access goes to the device, in order
+    Uncached,
+    /// Writes are buffered and combined, reads are uncached. Suited to
+    /// framebuffers.
+    WriteCombining,
+    /// Reads are cached and writes go straight to the device
+    WriteThrough,
+}
+
+impl CacheMode {
+    /// Returns the entry flags that select the mode
+    fn flags(&self) -> EntryFlags {
+        match *self {
+            CacheMode::Uncached => EntryFlags::CACHE_DISABLED | EntryFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining if PAT_ENABLED.load(Ordering::Relaxed) => EntryFlags::PAT,
+            // Without the PAT, uncached is the closest mode
+            CacheMode::WriteCombining => EntryFlags::CACHE_DISABLED | EntryFlags::WRITE_THROUGH,
+            CacheMode::WriteThrough => EntryFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// The reason device memory could not be mapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoremapError {
+    /// The mapping is zero bytes long
+    Empty,
+    /// The memory is RAM, which is already mapped write-back in the physmap
+    Ram,
+    /// There was no free range of kernel virtual memory large enough
+    OutOfVirtualMemory,
+}
+
+/// Device memory mapped by `ioremap`
+#[derive(Debug)]
+#[must_use = "An IoMapping must be unmapped with `iounmap`"]
+pub struct IoMapping {
+    region: Region,
+    address: VirtualAddress,
+    size: usize,
+}
+
+impl IoMapping {
+    /// Returns the virtual address of the first mapped physical address
+    pub fn address(&self) -> VirtualAddress {
+        self.address
+    }
+
+    /// Returns the size of the mapping in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns a pointer to the start of the mapping
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.address as *mut u8
+    }
+}
+
+/// Programs the page attribute table if the CPU supports it. This must be
+/// called before any device memory is mapped write-combining.
+pub fn init_pat() {
+    if !cpuid::has_pat() {
+        return;
+    }
+    unsafe {
+        wrmsr(IA32_PAT, PAT_VALUE);
+    }
+    tlb::flush_all();
+    PAT_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Maps the `size` bytes of device memory at `phys` into kernel memory with
+/// the caching given by `mode`.
+///
+/// RAM cannot be mapped, because it is also mapped write-back in the
+/// physmap.
+pub fn ioremap(phys: PhysicalAddress, size: usize, mode: CacheMode)
+    -> Result<IoMapping, IoremapError>
+{
+    if size == 0 {
+        return Err(IoremapError::Empty);
+    }
+    let start = Frame::containing_address(phys);
+    let end = Frame::containing_address(phys + size - 1);
+    let pages = end.0 - start.0 + 1;
+
+    let mut lock = MEMORY_CONTROLLER.lock();
+    let &mut MemoryController {
+        ref mut active_table,
+        ref mut frame_allocator,
+        ref mut regions,
+    } = lock.as_mut().unwrap();
+
+    // Only RAM is mapped in the physmap
+    if Frame::range_inclusive(start.clone(), end.clone())
+        .any(|frame| active_table.translate(PHYSMAP_BASE + frame.start_address()).is_some())
+    {
+        return Err(IoremapError::Ram);
+    }
+
+    let region = regions.allocate(pages).ok_or(IoremapError::OutOfVirtualMemory)?;
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE | mode.flags();
+    for (i, frame) in Frame::range_inclusive(start, end).enumerate() {
+        active_table.map_to(region.start_page() + i, frame, flags, frame_allocator)
+            .expect("ioremap region is already mapped");
+    }
+
+    Ok(IoMapping {
+        region: region,
+        address: region.start_address() + phys % PAGE_SIZE,
+        size: size,
+    })
+}
+
+/// Unmaps device memory mapped by `ioremap`
+pub fn iounmap(mapping: IoMapping) {
+    let mut lock = MEMORY_CONTROLLER.lock();
+    let &mut MemoryController {
+        ref mut active_table,
+        ref mut frame_allocator,
+        ref mut regions,
+    } = lock.as_mut().unwrap();
+
+    let start = mapping.region.start_page();
+    for page in Page::range_inclusive(start, start + (mapping.region.pages() - 1)) {
+        // The frames belong to the device, so they are not freed
+        active_table.unmap_frame(page, frame_allocator);
+    }
+    regions.free(mapping.region);
+}
+
+#[cfg(feature = "test")]
+pub mod tests {
+    use tap::TestGroup;
+    use memory::{MEMORY_CONTROLLER, allocate_frames, deallocate_frames};
+    use super::super::Page;
+    use super::{ioremap, iounmap, CacheMode, IoremapError, EntryFlags, PAT_ENABLED};
+    use core::sync::atomic::Ordering;
+
+    fn flags(address: usize) -> Option<EntryFlags> {
+        MEMORY_CONTROLLER.lock().as_ref().unwrap().active_table
+            .flags(Page::containing_address(address))
+    }
+
+    pub fn run() {
+        let mut tap = TestGroup::new(4);
+        tap.diagnostic("Testing ioremap");
+
+        // The VGA buffer is identity mapped, so it can be compared. Only
+        // uncached mappings may alias it.
+        let vga = 0xb8000;
+        let mapping = ioremap(vga + 2, 4, CacheMode::Uncached)
+            .expect("Could not ioremap the VGA buffer");
+        let same = unsafe {
+            *(mapping.as_mut_ptr() as *const u16) == *((vga + 2) as *const u16)
+        };
+        tap.assert_tap(same && mapping.address() % 0x1000 == 2,
+                       "ioremap does not map the right physical memory");
+        iounmap(mapping);
+
+        // The graphics memory below the text buffer is not mapped anywhere
+        // else. It is not accessed.
+        let mapping = ioremap(0xa0000, 0x1000, CacheMode::WriteCombining)
+            .expect("Could not ioremap graphics memory");
+        let expected = if PAT_ENABLED.load(Ordering::Relaxed) {
+            EntryFlags::PAT
+        } else {
+            EntryFlags::CACHE_DISABLED | EntryFlags::WRITE_THROUGH
+        };
+        let address = mapping.address();
+        tap.assert_tap(flags(address).map_or(false, |f| f.contains(expected)),
+                       "ioremap used the wrong cache attributes");
+
+        iounmap(mapping);
+        tap.assert_tap(flags(address).is_none(), "iounmap did not unmap the mapping");
+
+        let frame = allocate_frames(1, 1).expect("No more frames :(");
+        let ram = ioremap(frame.start_address(), 8, CacheMode::Uncached);
+        tap.assert_tap(ram.as_ref().err() == Some(&IoremapError::Ram),
+                       "RAM was mapped as device memory");
+        if let Ok(mapping) = ram {
+            iounmap(mapping);
+        }
+        deallocate_frames(frame, 1);
+    }
+}
@@ -7,7 +7,7 @@
 // This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use super::{VirtualAddress, PhysicalAddress, Page, ENTRY_COUNT};
+use super::{VirtualAddress, PhysicalAddress, Page, HugePage, GiantPage, ENTRY_COUNT};
 use super::entry::*;
 use super::table::{self, Table, Level4};
 use memory::{PAGE_SIZE, Frame, FrameAllocate, FrameDeallocate};
@@ -19,6 +19,26 @@ pub struct Mapper {
     p4: Unique<Table<Level4>>,
 }
 
+/// The reason a page could not be mapped
+#[derive(Debug)]
+pub enum MapError {
+    /// The page is already mapped to the given frame
+    AlreadyMapped(Frame),
+    /// The page is inside a huge page
+    InsideHugePage,
+}
+
+/// The size of a mapping
+#[derive(Clone, Copy)]
+enum MappingSize {
+    /// A 4KiB page in a p1 table
+    Normal,
+    /// A 2MiB page in a p2 table
+    Huge,
+    /// A 1GiB page in a p3 table
+    Giant,
+}
+
 impl Mapper {
     /// Returns a new Mapper.
     ///
@@ -85,22 +105,103 @@ impl Mapper {
             .or_else(huge_page)
     }
 
+    /// Returns the flags that the page is mapped with, or `None` if it is not
+    /// mapped. Huge pages are not supported.
+    pub fn flags(&self, page: Page) -> Option<EntryFlags> {
+        self.p4().next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2.next_table(page.p2_index()))
+            .map(|p1| p1[page.p1_index()].flags())
+            .and_then(|flags| if flags.contains(EntryFlags::PRESENT) { Some(flags) } else { None })
+    }
+
+    /// Changes the frame and flags that the mapped page uses. Returns the
+    /// frame that it was mapped to.
+    pub fn remap(&mut self, page: Page, frame: Frame, flags: EntryFlags) -> Frame {
+        let old_frame = {
+            let p1 = self.p4_mut()
+                .next_table_mut(page.p4_index())
+                .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                .expect("Cannot remap part of a huge page");
+
+            let old_frame = p1[page.p1_index()].pointed_frame().expect("Page is not mapped");
+            p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+            old_frame
+        };
+
+        use x86_64::VirtualAddress;
+        use x86_64::instructions::tlb;
+        tlb::flush(VirtualAddress(page.start_address()));
+
+        old_frame
+    }
+
     /// Maps the page to the frame with the provided flags
     /// The `PRESENT` flag is set by default. Needs an allocator as it might
     /// need to create new page tables
-    pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A) -> Result<(),Frame>
+    pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
+        -> Result<(), MapError>
         where A: FrameAllocate
     {
-        let p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
-        let p2 = p3.next_table_create(page.p3_index(), allocator);
-        let p1 = p2.next_table_create(page.p2_index(), allocator);
+        let p3 = self.p4_mut().next_table_create(page.p4_index(), allocator)
+            .ok_or(MapError::InsideHugePage)?;
+        let p2 = p3.next_table_create(page.p3_index(), allocator)
+            .ok_or(MapError::InsideHugePage)?;
+        let p1 = p2.next_table_create(page.p2_index(), allocator)
+            .ok_or(MapError::InsideHugePage)?;
 
         //assert!(p1[page.p1_index()].is_unused());
         if p1[page.p1_index()].is_unused() {
             p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
             Ok(())
         } else {
-            Err(p1[page.p1_index()].pointed_frame().unwrap())
+            Err(MapError::AlreadyMapped(p1[page.p1_index()].pointed_frame().unwrap()))
+        }
+    }
+
+    /// Maps the 2MiB page to the 512 frames starting at `frame` with the
+    /// provided flags. `frame` must be 2MiB aligned. Needs an allocator as it
+    /// might need to create new page tables.
+    pub fn map_huge_to<A>(&mut self, page: HugePage, frame: Frame, flags: EntryFlags,
+                          allocator: &mut A) -> Result<(), MapError>
+        where A: FrameAllocate
+    {
+        assert!(frame.0 % ENTRY_COUNT == 0, "Huge page frames must be 2MiB aligned");
+        let first = page.first_page();
+
+        let p3 = self.p4_mut().next_table_create(first.p4_index(), allocator)
+            .ok_or(MapError::InsideHugePage)?;
+        let p2 = p3.next_table_create(first.p3_index(), allocator)
+            .ok_or(MapError::InsideHugePage)?;
+
+        if p2[first.p2_index()].is_unused() {
+            p2[first.p2_index()].set(frame, flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE);
+            Ok(())
+        } else {
+            Err(MapError::AlreadyMapped(p2[first.p2_index()].pointed_frame().unwrap()))
+        }
+    }
+
+    /// Maps the 1GiB page to the 262144 frames starting at `frame` with the
+    /// provided flags. `frame` must be 1GiB aligned. Needs an allocator as it
+    /// might need to create a new p3 table.
+    pub fn map_giant_to<A>(&mut self, page: GiantPage, frame: Frame, flags: EntryFlags,
+                           allocator: &mut A) -> Result<(), MapError>
+        where A: FrameAllocate
+    {
+        assert!(frame.0 % (ENTRY_COUNT * ENTRY_COUNT) == 0,
+                "Giant page frames must be 1GiB aligned");
+        let first = page.first_page();
+
+        let p3 = self.p4_mut().next_table_create(first.p4_index(), allocator)
+            .ok_or(MapError::InsideHugePage)?;
+
+        if p3[first.p3_index()].is_unused() {
+            p3[first.p3_index()].set(frame, flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE);
+            Ok(())
+        } else {
+            Err(MapError::AlreadyMapped(p3[first.p3_index()].pointed_frame().unwrap()))
         }
     }
 
@@ -127,17 +228,29 @@ impl Mapper {
     /// given allocator
     pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
         where A: FrameDeallocate
+    {
+        let frame = self.unmap_frame(page, allocator);
+        allocator.deallocate_frame(frame);
+    }
+
+    /// Unmaps the given page and returns the frame that it was mapped to.
+    /// Any page tables that are left empty are freed to the given allocator.
+    pub fn unmap_frame<A>(&mut self, page: Page, allocator: &mut A) -> Frame
+        where A: FrameDeallocate
     {
         assert!(self.translate(page.start_address()).is_some());
 
-        let p1 = self.p4_mut()
-            .next_table_mut(page.p4_index())
-            .and_then(|p3| p3.next_table_mut(page.p3_index()))
-            .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("Mapping code does not support huge pages");
+        let frame = {
+            let p1 = self.p4_mut()
+                .next_table_mut(page.p4_index())
+                .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                .expect("Cannot unmap part of a huge page");
 
-        let frame = p1[page.p1_index()].pointed_frame().unwrap();
-        p1[page.p1_index()].set_unused();
+            let frame = p1[page.p1_index()].pointed_frame().unwrap();
+            p1[page.p1_index()].set_unused();
+            frame
+        };
 
         // Even after we update this value in memory,
         // it is still cached in the TLB in the CPU.
@@ -145,7 +258,89 @@ impl Mapper {
         use x86_64::instructions::tlb;
         tlb::flush(VirtualAddress(page.start_address()));
 
-        // TODO Free p(1,2,3) table if empty
-        allocator.deallocate_frame(frame);
+        self.free_empty_tables(page, MappingSize::Normal, allocator);
+        frame
+    }
+
+    /// Unmaps the given 2MiB page and returns the first of the frames that it
+    /// was mapped to. The frames are not freed, but any page tables that are
+    /// left empty are freed to the given allocator.
+    pub fn unmap_huge<A>(&mut self, page: HugePage, allocator: &mut A) -> Frame
+        where A: FrameDeallocate
+    {
+        let first = page.first_page();
+        let frame = {
+            let p2 = self.p4_mut()
+                .next_table_mut(first.p4_index())
+                .and_then(|p3| p3.next_table_mut(first.p3_index()))
+                .expect("Huge page is not mapped");
+
+            let entry = &mut p2[first.p2_index()];
+            assert!(entry.flags().contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE),
+                    "Huge page is not mapped");
+            let frame = entry.pointed_frame().unwrap();
+            entry.set_unused();
+            frame
+        };
+
+        use x86_64::VirtualAddress;
+        use x86_64::instructions::tlb;
+        tlb::flush(VirtualAddress(page.start_address()));
+
+        self.free_empty_tables(first, MappingSize::Huge, allocator);
+        frame
+    }
+
+    /// Unmaps the given 1GiB page and returns the first of the frames that it
+    /// was mapped to. The frames are not freed, but the p3 table is freed to
+    /// the given allocator if it is left empty.
+    pub fn unmap_giant<A>(&mut self, page: GiantPage, allocator: &mut A) -> Frame
+        where A: FrameDeallocate
+    {
+        let first = page.first_page();
+        let frame = {
+            let p3 = self.p4_mut()
+                .next_table_mut(first.p4_index())
+                .expect("Giant page is not mapped");
+
+            let entry = &mut p3[first.p3_index()];
+            assert!(entry.flags().contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE),
+                    "Giant page is not mapped");
+            let frame = entry.pointed_frame().unwrap();
+            entry.set_unused();
+            frame
+        };
+
+        use x86_64::VirtualAddress;
+        use x86_64::instructions::tlb;
+        tlb::flush(VirtualAddress(page.start_address()));
+
+        self.free_empty_tables(first, MappingSize::Giant, allocator);
+        frame
+    }
+
+    /// Frees the tables used to map `page` if they are empty. `size` is the
+    /// size of the mapping that was removed.
+    fn free_empty_tables<A>(&mut self, page: Page, size: MappingSize, allocator: &mut A)
+        where A: FrameDeallocate
+    {
+        let p2_freed = {
+            let p3 = self.p4_mut().next_table_mut(page.p4_index()).unwrap();
+            let p1_freed = match size {
+                MappingSize::Normal => p3.next_table_mut(page.p3_index()).unwrap()
+                    .free_next_table_if_empty(page.p2_index(), allocator),
+                _ => true,
+            };
+            match size {
+                MappingSize::Giant => true,
+                _ => p1_freed && p3.free_next_table_if_empty(page.p3_index(), allocator),
+            }
+        };
+
+        // The p3 tables of the higher half are shared by every address space,
+        // so they must stay
+        if p2_freed && page.p4_index() < ENTRY_COUNT / 2 {
+            self.p4_mut().free_next_table_if_empty(page.p4_index(), allocator);
+        }
     }
 }
@@ -7,31 +7,64 @@
 // This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use alloc::vec::Vec;
 use core::ops::{Deref, DerefMut};
 use core::ops::Add;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use multiboot2::{BootInformation, StringTable};
 use spin::Mutex;
 
 pub use self::entry::*;
-pub use self::mapper::Mapper;
-pub use self::temporary_page::{TemporaryPage, TinyAllocator};
+pub use self::mapper::{Mapper, MapError};
+pub use self::address_space::{AddressSpace, MappedRegion, switch_address_space};
+pub use self::ioremap::{ioremap, iounmap, init_pat, CacheMode, IoMapping, IoremapError};
+pub use self::walk::{walk, print_mappings, Walk, WalkStep, Mapping};
+pub use self::protection::{audit, init_protection};
+use self::table::{Table, Level1};
 use memory::{PAGE_SIZE, Frame, FrameAllocate};
 use memory::{MemoryController, MEMORY_CONTROLLER};
 
-use memory::frame_bitmap::FrameBitmap;
+use memory::buddy_allocator::BuddyAllocator;
 
 /// An entry in the page table.
 mod entry;
 /// Abstraction of the page table.
 mod table;
-/// A page to temporarily map a frame.
-mod temporary_page;
 /// An interface to the active page table.
 mod mapper;
+/// Separate address spaces that share the kernel.
+mod address_space;
+/// Mapping device memory.
+mod ioremap;
+/// Inspecting page tables.
+mod walk;
+/// Kernel memory protection.
+mod protection;
 
 /// How many entries are in each table.
 const ENTRY_COUNT: usize = 512;
+/// The size of a `HugePage` (2MiB)
+pub const HUGE_PAGE_SIZE: usize = ENTRY_COUNT * PAGE_SIZE;
+/// The size of a `GiantPage` (1GiB)
+pub const GIANT_PAGE_SIZE: usize = ENTRY_COUNT * HUGE_PAGE_SIZE;
+
+/// All physical memory is mapped linearly starting at this address
+pub const PHYSMAP_BASE: VirtualAddress = 0xFFFF_8000_0000_0000;
+/// The p4 entry that holds the physmap
+const PHYSMAP_INDEX: usize = 256;
+/// The size of the physmap, or zero if it is not yet mapped
+static PHYSMAP_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// The frame number of the kernel's P4 table
+static KERNEL_P4: AtomicUsize = AtomicUsize::new(0);
+/// The number of frames used by page tables that were created by the kernel
+static TABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of frames used by page tables, not counting the tables
+/// set up before boot
+pub fn table_frames() -> usize {
+    TABLE_FRAMES.load(Ordering::Relaxed)
+}
 
 /// This is the _only_ ActivePageTable that should be used in the system. Any others
 /// would violate the assumptions of `Unique`.
@@ -118,7 +151,7 @@ impl Page {
         let &mut MemoryController {
             ref mut active_table,
             ref mut frame_allocator,
-            stack_allocator: _,
+            regions: _,
         } = lock.as_mut().unwrap();
         active_table.map_to(self, frame, flags, frame_allocator)
             .expect("Unable to map frame because page is already taken");
@@ -133,7 +166,7 @@ impl Page {
         let &mut MemoryController {
             ref mut active_table,
             ref mut frame_allocator,
-            stack_allocator: _,
+            regions: _,
         } = lock.as_mut().unwrap();
         active_table.map(self, flags, frame_allocator);
 
@@ -145,7 +178,7 @@ impl Page {
         let &mut MemoryController {
             ref mut active_table,
             ref mut frame_allocator,
-            stack_allocator: _,
+            regions: _,
         } = lock.as_mut().unwrap();
 
         active_table.unmap(self, frame_allocator);
@@ -160,6 +193,156 @@ impl Add<usize> for Page {
     }
 }
 
+/// A 2MiB page, which is mapped directly by a p2 entry.
+#[derive(Debug, Copy, Clone)]
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+pub struct HugePage(usize);
+
+impl HugePage {
+    /// Returns the first address in the `HugePage`
+    pub fn start_address(&self) -> VirtualAddress {
+        self.0 * HUGE_PAGE_SIZE
+    }
+
+    /// Returns the `HugePage` containing the VirtualAddress given
+    ///
+    /// # Panics
+    /// A panic will occur if the address is not canonical.
+    pub fn containing_address(address: VirtualAddress) -> HugePage {
+        HugePage(Page::containing_address(address).0 / ENTRY_COUNT)
+    }
+
+    /// Returns the first 4KiB `Page` of the `HugePage`
+    fn first_page(&self) -> Page {
+        Page(self.0 * ENTRY_COUNT)
+    }
+}
+
+impl Add<usize> for HugePage {
+    type Output = HugePage;
+
+    fn add(self, rhs: usize) -> HugePage {
+        HugePage(self.0 + rhs)
+    }
+}
+
+/// A 1GiB page, which is mapped directly by a p3 entry.
+///
+/// These can only be used if the CPU supports them (`pdpe1gb` in CPUID).
+#[derive(Debug, Copy, Clone)]
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+pub struct GiantPage(usize);
+
+impl GiantPage {
+    /// Returns the first address in the `GiantPage`
+    pub fn start_address(&self) -> VirtualAddress {
+        self.0 * GIANT_PAGE_SIZE
+    }
+
+    /// Returns the `GiantPage` containing the VirtualAddress given
+    ///
+    /// # Panics
+    /// A panic will occur if the address is not canonical.
+    pub fn containing_address(address: VirtualAddress) -> GiantPage {
+        GiantPage(Page::containing_address(address).0 / (ENTRY_COUNT * ENTRY_COUNT))
+    }
+
+    /// Returns the first 4KiB `Page` of the `GiantPage`
+    fn first_page(&self) -> Page {
+        Page(self.0 * ENTRY_COUNT * ENTRY_COUNT)
+    }
+}
+
+/// Returns the address in the physmap of the given physical address.
+///
+/// # Panics
+/// Panics if the address is past the end of RAM. Only RAM is mapped, so the
+/// returned address faults if it is in a hole in the memory map.
+pub fn phys_to_virt(address: PhysicalAddress) -> VirtualAddress {
+    assert!(address < PHYSMAP_SIZE.load(Ordering::Relaxed),
+            "Physical address {:#x} is not in the physmap", address);
+    PHYSMAP_BASE + address
+}
+
+/// Translates a virtual address to the corresponding physical address using
+/// the current page table. Returns `None` if the address is not mapped.
+///
+/// Unlike `Mapper::translate` this does not use the recursive mapping, the
+/// tables are read through the physmap.
+pub fn virt_to_phys(address: VirtualAddress) -> Option<PhysicalAddress> {
+    use x86_64::registers::control_regs;
+
+    let page = Page::containing_address(address);
+    let p4 = physmap_table(Frame::containing_address(control_regs::cr3().0 as usize));
+
+    let p3 = physmap_table(p4[page.p4_index()].pointed_frame()?);
+    let p3_entry = &p3[page.p3_index()];
+    if p3_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+        return p3_entry.pointed_frame()
+            .map(|frame| frame.start_address() + address % GIANT_PAGE_SIZE);
+    }
+
+    let p2 = physmap_table(p3_entry.pointed_frame()?);
+    let p2_entry = &p2[page.p2_index()];
+    if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+        return p2_entry.pointed_frame()
+            .map(|frame| frame.start_address() + address % HUGE_PAGE_SIZE);
+    }
+
+    let p1 = physmap_table(p2_entry.pointed_frame()?);
+    p1[page.p1_index()].pointed_frame()
+        .map(|frame| frame.start_address() + address % PAGE_SIZE)
+}
+
+/// Returns the page table in `frame` through the physmap. It is returned as
+/// a `Table<Level1>` because it is not recursively mapped.
+fn physmap_table(frame: Frame) -> &'static mut Table<Level1> {
+    unsafe { &mut *(phys_to_virt(frame.start_address()) as *mut Table<Level1>) }
+}
+
+/// Maps every page of RAM to the physmap in the active table. Whole 2MiB
+/// chunks of RAM are mapped with huge pages, the edges of each memory area
+/// with 4KiB pages.
+///
+/// Holes in the memory map are left unmapped, mapping device memory as write
+/// back would alias it with a different memory type.
+fn map_physmap<A>(active_table: &mut ActivePageTable,
+                  boot_info: &BootInformation,
+                  allocator: &mut A)
+    where A: FrameAllocate
+{
+    let memory_map_tag = boot_info.memory_map_tag()
+        .expect("Memory map tag required");
+
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE | EntryFlags::GLOBAL;
+    let mut size = 0;
+    for area in memory_map_tag.memory_areas() {
+        // Only whole pages of the area are RAM
+        let start = (area.base_addr as usize + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let end = (area.base_addr + area.length) as usize / PAGE_SIZE * PAGE_SIZE;
+        assert!(end <= ENTRY_COUNT * GIANT_PAGE_SIZE, "Too much memory for the physmap");
+
+        let mut address = start;
+        while address < end {
+            if address % HUGE_PAGE_SIZE == 0 && address + HUGE_PAGE_SIZE <= end {
+                let page = HugePage::containing_address(PHYSMAP_BASE + address);
+                active_table.map_huge_to(page, Frame::containing_address(address),
+                                         flags, allocator)
+                    .expect("Memory areas overlap");
+                address += HUGE_PAGE_SIZE;
+            } else {
+                let page = Page::containing_address(PHYSMAP_BASE + address);
+                active_table.map_to(page, Frame::containing_address(address),
+                                    flags, allocator)
+                    .expect("Memory areas overlap");
+                address += PAGE_SIZE;
+            }
+        }
+        size = size.max(end);
+    }
+    PHYSMAP_SIZE.store(size, Ordering::Relaxed);
+}
+
 /// Identity map the given `Frame`
 fn identity_map(frame: Frame, flags: EntryFlags) {
         let page = Page::containing_address(frame.start_address());
@@ -219,36 +402,28 @@ impl ActivePageTable {
     /// Temporarily change the recursive mapping to the given table
     /// and execute the given closure in the new context.
     /// By return the table's state is restored.
-    pub fn with<F>(&mut self,
-                   table: &mut InactivePageTable,
-                   temporary_page: &mut TemporaryPage,
-                   f: F)
+    pub fn with<F>(&mut self, table: &mut InactivePageTable, f: F)
         where F: FnOnce(&mut Mapper)
     {
         use x86_64::registers::control_regs;
         use x86_64::instructions::tlb;
 
-        {
-            // Save table
-            let backup = Frame::containing_address(control_regs::cr3().0 as usize);
-
-            // Map temporary_page to the current table
-            let p4_table = temporary_page.map_table_frame(backup.clone(), self);
+        // Save table
+        let backup = Frame::containing_address(control_regs::cr3().0 as usize);
 
-            // Overwrite recursive mapping
-            self.p4_mut()[510].set(table.p4_frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
-            tlb::flush_all();
+        // The current table stays reachable through the physmap
+        let p4_table = physmap_table(backup.clone());
 
-            // Execute the closure in the new context
-            f(self);
+        // Overwrite recursive mapping
+        self.p4_mut()[510].set(table.p4_frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+        tlb::flush_all();
 
-            // Restore recursive mapping
-            p4_table[510].set(backup, EntryFlags::PRESENT | EntryFlags::WRITABLE);
-            tlb::flush_all();
-        }
+        // Execute the closure in the new context
+        f(self);
 
-        temporary_page.unmap(self);
-        temporary_page.allocator.flush(|_| {});
+        // Restore recursive mapping
+        p4_table[510].set(backup, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+        tlb::flush_all();
     }
 
     /// Activates the `InactivePageTable` given.
@@ -277,23 +452,18 @@ impl InactivePageTable {
     /// Creates a new `InactivePageTable`
     ///
     /// The `frame` is consumed and used to hold the inactive level 4 table. The table
-    /// that is returned has recursive mapping, so activating it is safe.
-    pub fn new(frame: Frame,
-               active_table: &mut ActivePageTable,
-               temporary_page: &mut TemporaryPage)
-               -> InactivePageTable {
-        {
-            let table = temporary_page.map_table_frame(frame.clone(), active_table);
-            // Now that it's mapped we can zero it
-            table.zero();
-            // Now set up recursive mapping for the table
-            table[510].set(frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
-        }
-
-        temporary_page.unmap(active_table);
-        // At this point, the p4 table still remains in the allocator, flush it
-        // to make sure that the `InactivePageTable` is the only owner
-        temporary_page.allocator.flush(|_| {});
+    /// that is returned has recursive mapping and shares the physmap of the
+    /// active table, so activating it is safe.
+    pub fn new(frame: Frame, active_table: &ActivePageTable) -> InactivePageTable {
+        let table = physmap_table(frame.clone());
+        table.zero();
+        TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+        // Now set up recursive mapping for the table
+        table[510].set(frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+
+        let physmap = &active_table.p4()[PHYSMAP_INDEX];
+        table[PHYSMAP_INDEX].set(physmap.pointed_frame().expect("Physmap is not mapped"),
+                                 physmap.flags());
 
         InactivePageTable { p4_frame: frame }
     }
@@ -302,25 +472,26 @@ impl InactivePageTable {
 /// Remaps the kernel using the given `ActivePageTable`
 ///
 /// Each kernel section is mapped to the higher half with the correct permissions.
-/// This function also identity maps the VGA text buffer and maps the multiboot2
-/// information structure to the higher half.
+/// This function also identity maps the VGA text buffer, maps the multiboot2
+/// information structure to the higher half and maps all of RAM to the physmap.
 pub fn remap_the_kernel<FA>(active_table: &mut ActivePageTable,
                             mut allocator: FA,
-                            boot_info: &BootInformation) -> FrameBitmap
+                            boot_info: &BootInformation) -> BuddyAllocator
     where FA: FrameAllocate
 {
     use memory::KERNEL_BASE;
 
-    // Create new inactive table using a temporary page
-    let mut temporary_page = 
-        TemporaryPage::new(Page(0xdeadbeef), &mut allocator);
+    // The physmap is mapped in the boot table first, the new table then
+    // shares it
+    map_physmap(active_table, boot_info, &mut allocator);
+
     let mut new_table = {
         let frame = allocator.allocate_frame()
             .expect("No more frames");
-        InactivePageTable::new(frame, &mut ACTIVE_TABLE.lock(), &mut temporary_page)
+        InactivePageTable::new(frame, active_table)
     };
 
-    active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+    active_table.with(&mut new_table, |mapper| {
 
         let elf_sections_tag = boot_info.elf_sections_tag()
             .expect("Memory map tag required");
@@ -337,8 +508,8 @@ pub fn remap_the_kernel<FA>(active_table: &mut ActivePageTable,
             }
             if string_table.section_name(&section) == ".init" {
                 // We do not map the init section because it is not
-                // used after boot
-                // FIXME do not leak these frames
+                // used after boot. Its frames are freed by
+                // `reclaim_boot_memory`
                 continue;
             }
             assert!(section.addr as usize % PAGE_SIZE == 0,
@@ -357,7 +528,8 @@ pub fn remap_the_kernel<FA>(active_table: &mut ActivePageTable,
 
         // Identity map the VGA buffer
         let vga_buffer = Frame::containing_address(0xb8000);
-        mapper.identity_map(vga_buffer, EntryFlags::WRITABLE, &mut allocator);
+        mapper.identity_map(vga_buffer, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+                            &mut allocator);
 
         // Map the multiboot info structure to the higher half
         let multiboot_start = Frame::containing_address(boot_info.start_address() - KERNEL_BASE);
@@ -367,43 +539,177 @@ pub fn remap_the_kernel<FA>(active_table: &mut ActivePageTable,
             let new_page = Page::containing_address(frame.start_address() + KERNEL_BASE);
             // if we have already mapped this page, it must have been
             // already mapped when we mapped the elf sections.
-            let _ = mapper.map_to(new_page, frame, EntryFlags::PRESENT, &mut allocator);
+            let _ = mapper.map_to(new_page, frame, EntryFlags::PRESENT | EntryFlags::NO_EXECUTE,
+                                  &mut allocator);
+        }
+
+        // Address spaces copy the upper half of the P4 table when they are
+        // created, so it must never change. Every P3 table is created now,
+        // and they are never freed.
+        for index in ENTRY_COUNT / 2..ENTRY_COUNT {
+            if index != 510 {
+                mapper.p4_mut().next_table_create(index, &mut allocator);
+            }
         }
     });
+    KERNEL_P4.store(new_table.p4_frame.0, Ordering::Relaxed);
     let old_table = active_table.switch(new_table);
     println!("New page table loaded");
 
     // Now, we're done allocating and need a struct with FrameDeallocate. Init
-    // the FrameBitmap
-    let mut frame_bitmap = FrameBitmap::new(allocator, active_table);
-
-    temporary_page.consume(&mut frame_bitmap);
+    // the BuddyAllocator
+    let mut buddy_allocator = BuddyAllocator::new(allocator, active_table);
 
     // Use the previous table as a guard page for the kernel stack
     let old_p4_page = Page::containing_address(old_table.p4_frame.start_address() + KERNEL_BASE);
-    active_table.unmap(old_p4_page, &mut frame_bitmap);
+    let old_p4_frame = active_table.unmap_frame(old_p4_page, &mut buddy_allocator);
+    buddy_allocator.reclaim_frame(old_p4_frame);
 
     println!("New guard page at {:#x}", old_p4_page.start_address());
 
-    frame_bitmap
+    buddy_allocator
+}
+
+/// The frames that are only used during boot, as found by `boot_frames`
+pub struct BootFrames {
+    init: Vec<Frame>,
+    multiboot: Vec<Frame>,
+}
+
+/// Finds the frames that are only used during boot: the `.init` section and
+/// the multiboot2 information structure.
+pub fn boot_frames(boot_info: &BootInformation) -> BootFrames {
+    use memory::KERNEL_BASE;
+
+    let mut init_frames = Vec::new();
+    let mut multiboot_frames = Vec::new();
+    let elf_sections_tag = boot_info.elf_sections_tag()
+        .expect("Memory map tag required");
+    let string_table = unsafe {
+        &*((elf_sections_tag.string_table() as *const StringTable).offset(KERNEL_BASE as isize))
+    };
+
+    // The physical address range of each loaded section
+    let physical = |start: usize, end: usize| if start >= KERNEL_BASE {
+        (start - KERNEL_BASE, end - KERNEL_BASE)
+    } else {
+        (start, end)
+    };
+
+    for section in elf_sections_tag.sections().filter(|s| s.is_allocated()) {
+        if string_table.section_name(&section) == ".init" {
+            let (start, end) = physical(section.start_address(), section.end_address());
+            init_frames.extend(Frame::range_inclusive(Frame::containing_address(start),
+                                                      Frame::containing_address(end - 1)));
+        }
+    }
+
+    // Frames of the information structure may also hold parts of the
+    // kernel or of a module, which are still used
+    let in_use = |frame: &Frame| {
+        let start = frame.start_address();
+        let end = start + PAGE_SIZE;
+        elf_sections_tag.sections()
+            .filter(|s| s.is_allocated())
+            .map(|s| physical(s.start_address(), s.end_address()))
+            .chain(boot_info.module_tags()
+                   .map(|m| (m.start_address() as usize, m.end_address() as usize)))
+            .any(|(s, e)| s < end && start < e)
+    };
+    let multiboot_start = Frame::containing_address(boot_info.start_address() - KERNEL_BASE);
+    let multiboot_end = Frame::containing_address((boot_info.end_address() - KERNEL_BASE) - 1);
+    multiboot_frames.extend(Frame::range_inclusive(multiboot_start, multiboot_end)
+                            .filter(|frame| !in_use(frame)));
+
+    BootFrames {
+        init: init_frames,
+        multiboot: multiboot_frames,
+    }
+}
+
+/// Gives the memory that is only used during boot to `allocator`: the boot
+/// page tables and `frames`. Returns the number of bytes reclaimed.
+///
+/// The multiboot2 information structure is unmapped, so it must not be used
+/// afterwards.
+pub fn reclaim_boot_memory(active_table: &mut ActivePageTable,
+                           allocator: &mut BuddyAllocator,
+                           frames: &BootFrames) -> usize
+{
+    use memory::KERNEL_BASE;
+
+    extern "C" {
+        static kstack_late_bottom: usize;
+        static kstack_bottom: usize;
+    }
+
+    let mut reclaimed = 0;
+
+    // The init section is not mapped by `remap_the_kernel`
+    for frame in frames.init.iter() {
+        if allocator.reclaim_frame(frame.clone()) {
+            reclaimed += 1;
+        }
+    }
+
+    for frame in frames.multiboot.iter() {
+        let page = Page::containing_address(frame.start_address() + KERNEL_BASE);
+        let frame = active_table.unmap_frame(page, allocator);
+        if allocator.reclaim_frame(frame) {
+            reclaimed += 1;
+        }
+    }
+
+    // The boot tables sit below the boot stack. Once unmapped they extend
+    // its guard page.
+    let (tables_start, tables_end) = unsafe {
+        (&kstack_late_bottom as *const _ as usize, &kstack_bottom as *const _ as usize)
+    };
+    for page in Page::range_inclusive(Page::containing_address(tables_start),
+                                      Page::containing_address(tables_end - 1)) {
+        let frame = active_table.unmap_frame(page, allocator);
+        if allocator.reclaim_frame(frame) {
+            reclaimed += 1;
+        }
+    }
+
+    reclaimed * PAGE_SIZE
 }
 
 #[cfg(feature = "test")]
 pub mod tests {
 
     use memory::{MemoryController,MEMORY_CONTROLLER,FrameAllocate};
-    use super::Page;
+    use memory::buddy_allocator::BuddyAllocator;
+    use memory::{Frame, PAGE_SIZE};
+    use super::{ActivePageTable, MapError, Page, HugePage, GiantPage};
+    use super::{HUGE_PAGE_SIZE, GIANT_PAGE_SIZE};
+    use super::{phys_to_virt, virt_to_phys, PHYSMAP_BASE};
     use super::entry::EntryFlags;
     use tap::TestGroup;
 
     pub fn run() {
-        let mut lock = MEMORY_CONTROLLER.lock();
-        let &mut MemoryController {
-            ref mut active_table,
-            ref mut frame_allocator,
-            stack_allocator: _,
-        } = lock.as_mut().unwrap();
+        {
+            let mut lock = MEMORY_CONTROLLER.lock();
+            let &mut MemoryController {
+                ref mut active_table,
+                ref mut frame_allocator,
+                regions: _,
+            } = lock.as_mut().unwrap();
+
+            test_mappings(active_table, frame_allocator);
+            test_table_reclaim(active_table, frame_allocator);
+            test_huge_pages(active_table, frame_allocator);
+            test_physmap(active_table, frame_allocator);
+        }
+        super::address_space::tests::run();
+        super::ioremap::tests::run();
+        super::walk::tests::run();
+        super::protection::tests::run();
+    }
 
+    fn test_mappings(active_table: &mut ActivePageTable,
+                     frame_allocator: &mut BuddyAllocator) {
         let mut tap = TestGroup::new(7);
         tap.diagnostic("Testing page table mappings");
         // Address 0 should not be mappd
@@ -420,10 +726,10 @@ pub mod tests {
             active_table.mapper.translate(::memory::HEAP_START,).is_some(),
             "Heap not mapped!");
 
-        // frame bitmap should be mapped (check first page)
+        // buddy allocator bitmaps should be mapped (check first page)
         tap.assert_tap(
-            active_table.mapper.translate(::memory::frame_bitmap::BITMAP_BASE)
-                .is_some(), "Frame bitmap not mapped!");
+            active_table.mapper.translate(::memory::buddy_allocator::BUDDY_BASE)
+                .is_some(), "Buddy allocator bitmap not mapped!");
 
         tap.diagnostic("Testing `map_to`");
         // Test map_to
@@ -444,4 +750,118 @@ pub mod tests {
                        "Did non successfully unmap test page (12th P3)");
     }
 
+    fn test_table_reclaim(active_table: &mut ActivePageTable,
+                          frame_allocator: &mut BuddyAllocator) {
+        let mut tap = TestGroup::new(2);
+        tap.diagnostic("Testing page table reclamation");
+
+        // Two full p1 tables in an otherwise unused p4 entry
+        let start = Page::containing_address(0o001_000_000_000_0000);
+        let end = start + 1023;
+        let free = frame_allocator.free_frames();
+
+        for _ in 0..4 {
+            for page in Page::range_inclusive(start, end) {
+                active_table.map(page, EntryFlags::WRITABLE, frame_allocator);
+            }
+            for page in Page::range_inclusive(start, end) {
+                active_table.unmap(page, frame_allocator);
+            }
+        }
+
+        tap.assert_tap(active_table.mapper.p4()[start.p4_index()].is_unused(),
+                       "p3 table was not freed after unmapping");
+        tap.assert_tap(frame_allocator.free_frames() == free,
+                       "Page tables leaked frames after unmapping");
+    }
+
+    fn test_huge_pages(active_table: &mut ActivePageTable,
+                       frame_allocator: &mut BuddyAllocator) {
+        use cpuio::cpuid;
+
+        let mut tap = TestGroup::new(8);
+        tap.diagnostic("Testing huge pages");
+
+        let free = frame_allocator.free_frames();
+        let addr = 0o001_000_003_000_0000; // 4th p2 entry of the 2nd p4 entry
+        let page = HugePage::containing_address(addr);
+        let frame = frame_allocator.allocate_frames(512, 512)
+            .expect("No more frames :(");
+
+        let res = active_table.map_huge_to(page, frame.clone(), EntryFlags::WRITABLE,
+                                           frame_allocator);
+        tap.assert_tap(res.is_ok(), "Unable to map a huge page");
+        tap.assert_tap(
+            active_table.translate(addr + 0x1234) == Some(frame.start_address() + 0x1234),
+            "Huge page translated to the wrong address");
+        tap.assert_tap(
+            active_table.translate(addr + HUGE_PAGE_SIZE - 1) ==
+                Some(frame.start_address() + HUGE_PAGE_SIZE - 1),
+            "End of huge page translated to the wrong address");
+        tap.assert_tap(active_table.translate(addr + HUGE_PAGE_SIZE).is_none(),
+                       "Huge page mapped more than 2MiB");
+        let inside = active_table.map_to(Page::containing_address(addr + PAGE_SIZE),
+                                         Frame::containing_address(0), EntryFlags::empty(),
+                                         frame_allocator);
+        tap.assert_tap(match inside { Err(MapError::InsideHugePage) => true, _ => false },
+                       "Page inside a huge page was mapped");
+
+        let unmapped = active_table.unmap_huge(page, frame_allocator);
+        tap.assert_tap(unmapped == frame && active_table.translate(addr).is_none(),
+                       "Did not successfully unmap huge page");
+        frame_allocator.deallocate_frames(unmapped, 512);
+
+        // Giant pages are only translated, never touched, so the first GiB of
+        // physical memory can be used whether or not it exists
+        if cpuid::has_giant_pages() {
+            let addr = 0o002_000_000_000_0000;
+            let page = GiantPage::containing_address(addr);
+            active_table.map_giant_to(page, Frame::containing_address(0), EntryFlags::empty(),
+                                      frame_allocator)
+                .expect("Unable to map a giant page");
+            tap.assert_tap(
+                active_table.translate(addr + GIANT_PAGE_SIZE - 1) == Some(GIANT_PAGE_SIZE - 1),
+                "Giant page translated to the wrong address");
+            active_table.unmap_giant(page, frame_allocator);
+        } else {
+            tap.skip("1GiB pages are not supported");
+        }
+
+        tap.assert_tap(frame_allocator.free_frames() == free,
+                       "Huge pages leaked frames");
+    }
+
+    fn test_physmap(active_table: &mut ActivePageTable,
+                    frame_allocator: &mut BuddyAllocator) {
+        let mut tap = TestGroup::new(6);
+        tap.diagnostic("Testing the physmap");
+
+        let frame = frame_allocator.allocate_frame()
+            .expect("No more frames :(");
+        let phys = frame.start_address();
+        tap.assert_tap(virt_to_phys(phys_to_virt(phys)) == Some(phys),
+                       "Physmap address does not translate back");
+
+        // Writes through another mapping should be seen in the physmap
+        let addr = 0o001_000_000_000_0000;
+        active_table.map_to(Page::containing_address(addr), frame, EntryFlags::WRITABLE,
+                            frame_allocator)
+            .expect("Test page was already mapped");
+        unsafe {
+            *(addr as *mut u64) = 0xdead_beef;
+        }
+        tap.assert_tap(unsafe { *(phys_to_virt(phys) as *const u64) } == 0xdead_beef,
+                       "Physmap does not alias the mapped frame");
+        tap.assert_tap(virt_to_phys(addr + 8) == Some(phys + 8),
+                       "Mapped page translated to the wrong address");
+        active_table.unmap(Page::containing_address(addr), frame_allocator);
+
+        tap.assert_tap(virt_to_phys(::memory::HEAP_START) ==
+                           active_table.translate(::memory::HEAP_START),
+                       "Physmap walk disagrees with `translate`");
+        tap.assert_tap(virt_to_phys(0).is_none(), "Address 0 translated");
+        // The VGA buffer is not RAM
+        tap.assert_tap(virt_to_phys(PHYSMAP_BASE + 0xb8000).is_none(),
+                       "Memory map hole is mapped in the physmap");
+    }
 }
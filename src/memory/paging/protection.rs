@@ -0,0 +1,124 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Kernel memory protection
+//!
+//! No kernel mapping may be both writable and executable, and only the
+//! kernel image may be executable. `audit` checks both rules against the
+//! active page table.
+
+use cpuio::cpuid;
+use memory::{KERNEL_BASE, MEMORY_CONTROLLER};
+use super::{EntryFlags, Mapping};
+
+/// User mode instruction prevention
+const CR4_UMIP: usize = 1 << 11;
+/// Supervisor mode execution prevention
+const CR4_SMEP: usize = 1 << 20;
+/// Supervisor mode access prevention
+const CR4_SMAP: usize = 1 << 21;
+
+/// Returns the protections in CR4 that the CPU supports
+fn supported_protections() -> usize {
+    let mut bits = 0;
+    if cpuid::has_umip() {
+        bits |= CR4_UMIP;
+    }
+    if cpuid::has_smep() {
+        bits |= CR4_SMEP;
+    }
+    if cpuid::has_smap() {
+        bits |= CR4_SMAP;
+    }
+    bits
+}
+
+fn read_cr4() -> usize {
+    let value: usize;
+    unsafe {
+        asm!("mov $0, cr4" : "=r"(value) ::: "intel", "volatile");
+    }
+    value
+}
+
+/// Enables SMEP, SMAP and UMIP if the CPU supports them.
+///
+/// Once SMAP is enabled the kernel cannot access user accessible pages, and
+/// once SMEP is enabled it cannot execute them.
+pub fn init_protection() {
+    let value = read_cr4() | supported_protections();
+    unsafe {
+        asm!("mov cr4, $0" :: "r"(value) : "memory" : "intel", "volatile");
+    }
+}
+
+/// Returns the rule that `mapping` breaks, if any
+fn violation(mapping: &Mapping) -> Option<&'static str> {
+    let flags = mapping.flags();
+    if flags.contains(EntryFlags::NO_EXECUTE) {
+        None
+    } else if flags.contains(EntryFlags::WRITABLE) {
+        Some("writable and executable")
+    } else if mapping.start_address() < KERNEL_BASE {
+        Some("executable outside of the kernel image")
+    } else {
+        None
+    }
+}
+
+/// Calls `f` with every mapping of the active page table that breaks the
+/// kernel's protection rules, and a description of the rule. Returns the
+/// number of violations.
+pub fn audit<F>(mut f: F) -> usize
+    where F: FnMut(&Mapping, &'static str)
+{
+    let mut count = 0;
+    let lock = MEMORY_CONTROLLER.lock();
+    lock.as_ref().unwrap().active_table.for_each_mapping(|mapping| {
+        if let Some(rule) = violation(mapping) {
+            f(mapping, rule);
+            count += 1;
+        }
+    });
+    count
+}
+
+#[cfg(feature = "test")]
+pub mod tests {
+    use tap::TestGroup;
+    use memory::{HEAP_START, alloc_stack};
+    use super::super::{walk, EntryFlags};
+    use super::{audit, read_cr4, supported_protections};
+
+    /// Returns true if `address` is mapped and not executable
+    fn no_execute(address: usize) -> bool {
+        let walk = walk(address);
+        walk.physical_address().is_some() && walk.steps().iter().filter_map(|step| *step)
+            .any(|step| step.flags.contains(EntryFlags::NO_EXECUTE))
+    }
+
+    pub fn run() {
+        let mut tap = TestGroup::new(3);
+        tap.diagnostic("Auditing kernel memory protection");
+
+        let violations = audit(|mapping, rule| {
+            serial_println!("# {} is {}", mapping, rule);
+        });
+        tap.assert_tap(violations == 0, "Kernel mappings break W^X");
+
+        let stack = alloc_stack(1).expect("Could not allocate a stack");
+        tap.assert_tap(no_execute(HEAP_START) && no_execute(stack.bottom()),
+                       "Heap or stacks are executable");
+        drop(stack);
+
+        let supported = supported_protections();
+        tap.assert_tap(read_cr4() & supported == supported,
+                       "Supported protections are not enabled");
+    }
+}
@@ -17,9 +17,11 @@
 use memory::paging::entry::*;
 use memory::paging::ENTRY_COUNT;
 use memory::paging::VirtualAddress;
-use memory::FrameAllocate;
+use memory::paging::TABLE_FRAMES;
+use memory::{FrameAllocate, FrameDeallocate};
 
 use core::marker::PhantomData;
+use core::sync::atomic::Ordering;
 use core::ops::{Index, IndexMut};
 
 /// A pointer to the level 4 page table
@@ -43,6 +45,11 @@ impl<L> Table<L>
             entry.set_unused();
         }
     }
+
+    /// Returns `true` if every entry is unused
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
+    }
 }
 
 /// These methods can only be used if the given table is a parent to other tables.
@@ -74,23 +81,49 @@ impl<L> Table<L>
     }
 
     /// If the next table does not exist, this function creates it with the physical
-    /// frame allocator given and returns a mutable reference.
+    /// frame allocator given and returns a mutable reference. Returns `None` if
+    /// the entry maps a huge page instead of a table.
     pub fn next_table_create<A>(&mut self,
                                 index: usize,
                                 allocator: &mut A)
-                                -> &mut Table<L::NextLevel>
+                                -> Option<&mut Table<L::NextLevel>>
         where A: FrameAllocate
     {
         if self.next_table(index).is_none() {
-            assert!(!self.entries[index].flags().contains(EntryFlags::HUGE_PAGE),
-                    "Mapping code does not support huge pages");
+            if self.entries[index].flags().contains(EntryFlags::HUGE_PAGE) {
+                return None;
+            }
             let frame = allocator.allocate_frame()
                 .expect("No frames availible :(");
             self.entries[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
             self.next_table_mut(index).unwrap().zero();
             assert!(self.next_table_mut(index).is_some());
+            TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+        }
+        self.next_table_mut(index)
+    }
+
+    /// If the table at `index` exists and is empty, this function removes it
+    /// and frees its frame to the given allocator. Returns `true` if the table
+    /// was freed.
+    pub fn free_next_table_if_empty<A>(&mut self, index: usize, allocator: &mut A) -> bool
+        where A: FrameDeallocate
+    {
+        let empty = self.next_table(index).map_or(false, |table| table.is_empty());
+        if empty {
+            let address = self.next_table_address(index).unwrap();
+            let frame = self[index].pointed_frame().unwrap();
+            self[index].set_unused();
+
+            // The table is still cached in the TLB through the recursive
+            // mapping
+            use x86_64::instructions::tlb;
+            tlb::flush(::x86_64::VirtualAddress(address));
+
+            allocator.deallocate_frame(frame);
+            TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
         }
-        self.next_table_mut(index).unwrap()
+        empty
     }
 }
 
@@ -0,0 +1,338 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Inspecting page tables
+//!
+//! The active page table can be walked for a single address, showing the
+//! entry used at every level, or walked in full to list every mapped range.
+//! Neither allocates, so both can be used while handling a fault.
+
+use core::fmt;
+
+use memory::{PAGE_SIZE, MEMORY_CONTROLLER};
+use super::{VirtualAddress, PhysicalAddress, EntryFlags, Mapper};
+use super::{ENTRY_COUNT, HUGE_PAGE_SIZE, GIANT_PAGE_SIZE};
+use super::entry::Entry;
+
+/// The size of the memory mapped by a p4 entry (512GiB)
+const P4_ENTRY_SIZE: usize = ENTRY_COUNT * GIANT_PAGE_SIZE;
+/// The p4 entry that maps the page table recursively
+const RECURSIVE_INDEX: usize = 510;
+
+/// The entry used to translate an address at one level of the page table
+#[derive(Debug, Clone, Copy)]
+pub struct WalkStep {
+    /// The index of the entry in its table
+    pub index: usize,
+    /// The raw flags of the entry
+    pub flags: EntryFlags,
+    /// The physical address that the entry points to, if it is present
+    pub address: Option<PhysicalAddress>,
+}
+
+impl WalkStep {
+    fn new(entry: &Entry, index: usize) -> WalkStep {
+        WalkStep {
+            index: index,
+            flags: entry.flags(),
+            address: entry.pointed_frame().map(|frame| frame.start_address()),
+        }
+    }
+}
+
+/// Every entry used to translate an address, from the p4 table down
+#[derive(Debug, Clone, Copy)]
+pub struct Walk {
+    address: VirtualAddress,
+    /// The entry at each level, starting with the p4 table. Levels below a
+    /// missing or huge entry are `None`.
+    steps: [Option<WalkStep>; 4],
+}
+
+impl Walk {
+    /// Returns the entries that were used, starting with the p4 entry
+    pub fn steps(&self) -> &[Option<WalkStep>] {
+        &self.steps
+    }
+
+    /// Returns the physical address that the address translates to, or
+    /// `None` if it is not mapped
+    pub fn physical_address(&self) -> Option<PhysicalAddress> {
+        let (level, step) = self.steps.iter().enumerate()
+            .filter_map(|(i, step)| step.map(|step| (i, step)))
+            .last()?;
+        let size = match level {
+            1 => GIANT_PAGE_SIZE,
+            2 => HUGE_PAGE_SIZE,
+            3 => PAGE_SIZE,
+            _ => return None,
+        };
+        if level < 3 && !step.flags.contains(EntryFlags::HUGE_PAGE) {
+            return None;
+        }
+        step.address.map(|start| start + self.address % size)
+    }
+}
+
+impl fmt::Display for Walk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Page table walk for {:#x}:", self.address)?;
+        for (level, step) in self.steps.iter().enumerate() {
+            let step = match *step {
+                Some(step) => step,
+                None => break,
+            };
+            write!(f, "\n  P{}[{:3}]: ", 4 - level, step.index)?;
+            match step.address {
+                Some(address) => write!(f, "{:#014x} {:?}", address, step.flags)?,
+                None => write!(f, "not present")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A range of virtual memory that is mapped with the same flags
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    start: VirtualAddress,
+    end: VirtualAddress,
+    flags: EntryFlags,
+}
+
+impl Mapping {
+    /// Returns the first address in the range
+    pub fn start_address(&self) -> VirtualAddress {
+        self.start
+    }
+
+    /// Returns the address just past the end of the range
+    pub fn end_address(&self) -> VirtualAddress {
+        self.end
+    }
+
+    /// Returns the flags the range is mapped with, including the restrictions
+    /// of every table above it. `ACCESSED` and `DIRTY` are never set.
+    pub fn flags(&self) -> EntryFlags {
+        self.flags
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+        write!(f, "{:#018x}-{:#018x} {:>9} KiB r{}{}{}{}{}",
+               self.start, self.end, (self.end - self.start) / 1024,
+               flag(EntryFlags::WRITABLE, 'w'),
+               if self.flags.contains(EntryFlags::NO_EXECUTE) { '-' } else { 'x' },
+               flag(EntryFlags::USER_ACCESSIBLE, 'u'),
+               flag(EntryFlags::GLOBAL, 'g'),
+               flag(EntryFlags::COPY_ON_WRITE, 'c'))
+    }
+}
+
+/// Joins adjacent mappings with the same flags before passing them on
+struct Coalescer<F: FnMut(&Mapping)> {
+    current: Option<Mapping>,
+    f: F,
+}
+
+impl<F: FnMut(&Mapping)> Coalescer<F> {
+    fn add(&mut self, start: VirtualAddress, size: usize, flags: EntryFlags) {
+        if let Some(ref mut current) = self.current {
+            if current.end == start && current.flags == flags {
+                current.end += size;
+                return;
+            }
+        }
+        self.finish();
+        self.current = Some(Mapping {
+            start: start,
+            end: start + size,
+            flags: flags,
+        });
+    }
+
+    fn finish(&mut self) {
+        if let Some(mapping) = self.current.take() {
+            (self.f)(&mapping);
+        }
+    }
+}
+
+/// Returns the flags of `entry` as restricted by the flags of the tables
+/// above it, `parent`
+fn effective_flags(parent: EntryFlags, entry: &Entry) -> EntryFlags {
+    let mut flags = entry.flags();
+    flags.remove(EntryFlags::ACCESSED | EntryFlags::DIRTY);
+    flags.remove((EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE) - parent);
+    flags.insert(parent & EntryFlags::NO_EXECUTE);
+    flags
+}
+
+/// Sign extends a 48 bit address
+fn canonical(address: VirtualAddress) -> VirtualAddress {
+    if address & (1 << 47) != 0 {
+        address | 0xffff_0000_0000_0000
+    } else {
+        address
+    }
+}
+
+impl Mapper {
+    /// Returns the entries used to translate `address`
+    pub fn walk(&self, address: VirtualAddress) -> Walk {
+        let index = |level: usize| (address >> (12 + 9 * level)) & 0o777;
+        let mut walk = Walk {
+            address: address,
+            steps: [None; 4],
+        };
+
+        let p4 = self.p4();
+        walk.steps[0] = Some(WalkStep::new(&p4[index(3)], index(3)));
+        let p3 = match p4.next_table(index(3)) {
+            Some(p3) => p3,
+            None => return walk,
+        };
+        walk.steps[1] = Some(WalkStep::new(&p3[index(2)], index(2)));
+        let p2 = match p3.next_table(index(2)) {
+            Some(p2) => p2,
+            None => return walk,
+        };
+        walk.steps[2] = Some(WalkStep::new(&p2[index(1)], index(1)));
+        let p1 = match p2.next_table(index(1)) {
+            Some(p1) => p1,
+            None => return walk,
+        };
+        walk.steps[3] = Some(WalkStep::new(&p1[index(0)], index(0)));
+        walk
+    }
+
+    /// Calls `f` with every mapped range, in order of address. Adjacent pages
+    /// with the same flags are joined into one range. The recursive mapping
+    /// is skipped.
+    pub fn for_each_mapping<F>(&self, f: F)
+        where F: FnMut(&Mapping)
+    {
+        let mut out = Coalescer {
+            current: None,
+            f: f,
+        };
+        let top = EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE;
+
+        let p4 = self.p4();
+        for i4 in (0..ENTRY_COUNT).filter(|&i| i != RECURSIVE_INDEX) {
+            let p3 = match p4.next_table(i4) {
+                Some(p3) => p3,
+                None => continue,
+            };
+            let flags4 = effective_flags(top, &p4[i4]);
+            let base4 = canonical(i4 * P4_ENTRY_SIZE);
+
+            for i3 in 0..ENTRY_COUNT {
+                let entry = &p3[i3];
+                let base3 = base4 + i3 * GIANT_PAGE_SIZE;
+                let flags3 = effective_flags(flags4, entry);
+                let p2 = match p3.next_table(i3) {
+                    Some(p2) => p2,
+                    None => {
+                        if entry.flags().contains(EntryFlags::PRESENT) {
+                            out.add(base3, GIANT_PAGE_SIZE, flags3 - EntryFlags::HUGE_PAGE);
+                        }
+                        continue;
+                    },
+                };
+
+                for i2 in 0..ENTRY_COUNT {
+                    let entry = &p2[i2];
+                    let base2 = base3 + i2 * HUGE_PAGE_SIZE;
+                    let flags2 = effective_flags(flags3, entry);
+                    let p1 = match p2.next_table(i2) {
+                        Some(p1) => p1,
+                        None => {
+                            if entry.flags().contains(EntryFlags::PRESENT) {
+                                out.add(base2, HUGE_PAGE_SIZE, flags2 - EntryFlags::HUGE_PAGE);
+                            }
+                            continue;
+                        },
+                    };
+
+                    for i1 in 0..ENTRY_COUNT {
+                        let entry = &p1[i1];
+                        if entry.flags().contains(EntryFlags::PRESENT) {
+                            out.add(base2 + i1 * PAGE_SIZE, PAGE_SIZE,
+                                    effective_flags(flags2, entry));
+                        }
+                    }
+                }
+            }
+        }
+        out.finish();
+    }
+}
+
+/// Returns the entries used to translate `address` in the active page table.
+///
+/// This does not take the memory controller's lock, so it can be used while
+/// handling a fault. The result may be stale if the table is being changed.
+pub fn walk(address: VirtualAddress) -> Walk {
+    // Only reads the table through the recursive mapping, which is always
+    // valid
+    unsafe { Mapper::new() }.walk(address)
+}
+
+/// Prints every mapped range of the active page table over serial
+pub fn print_mappings() {
+    let lock = MEMORY_CONTROLLER.lock();
+    lock.as_ref().unwrap().active_table.for_each_mapping(|mapping| {
+        serial_println!("# {}", mapping);
+    });
+}
+
+#[cfg(feature = "test")]
+pub mod tests {
+    use tap::TestGroup;
+    use memory::{PAGE_SIZE, MEMORY_CONTROLLER};
+    use memory::vmalloc::{vmalloc, vfree, Guard};
+    use super::super::EntryFlags;
+    use super::walk;
+
+    pub fn run() {
+        let mut tap = TestGroup::new(3);
+        tap.diagnostic("Testing page table walks");
+
+        let area = vmalloc(4 * PAGE_SIZE, Guard::BEFORE | Guard::AFTER)
+            .expect("Could not vmalloc");
+        let start = area.start_address();
+        let end = start + 4 * PAGE_SIZE;
+
+        let mut found = false;
+        {
+            let lock = MEMORY_CONTROLLER.lock();
+            lock.as_ref().unwrap().active_table.for_each_mapping(|mapping| {
+                found |= mapping.start_address() == start && mapping.end_address() == end &&
+                    mapping.flags().contains(EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+            });
+        }
+        tap.assert_tap(found, "Area guarded by unmapped pages was not a single mapping");
+
+        let translated = MEMORY_CONTROLLER.lock().as_ref().unwrap()
+            .active_table.translate(start + 8);
+        let walked = walk(start + 8);
+        tap.assert_tap(walked.steps().iter().all(|step| step.is_some()) &&
+                           walked.physical_address() == translated,
+                       "Walk does not match translation");
+
+        let guard = walk(end);
+        tap.assert_tap(guard.physical_address().is_none(),
+                       "Walk of a guard page found a frame");
+
+        vfree(area);
+    }
+}
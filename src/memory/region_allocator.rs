@@ -0,0 +1,230 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Allocator for ranges of kernel virtual memory
+//!
+//! Free ranges are kept on the heap in a list sorted by address, so the
+//! allocator can only be created once the heap exists.
+
+use alloc::vec::Vec;
+
+use memory::PAGE_SIZE;
+use memory::paging::{Page, VirtualAddress};
+
+/// A range of virtual pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    start: VirtualAddress,
+    pages: usize,
+}
+
+impl Region {
+    /// Returns the first address in the region
+    pub fn start_address(&self) -> VirtualAddress {
+        self.start
+    }
+
+    /// Returns the address just past the end of the region
+    pub fn end_address(&self) -> VirtualAddress {
+        self.start + self.pages * PAGE_SIZE
+    }
+
+    /// Returns the first page in the region
+    pub fn start_page(&self) -> Page {
+        Page::containing_address(self.start)
+    }
+
+    /// Returns the number of pages in the region
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+}
+
+/// A first fit allocator of virtual `Region`s
+pub struct RegionAllocator {
+    /// The free ranges, sorted by address
+    free: Vec<Region>,
+    /// The number of pages managed by the allocator
+    total: usize,
+    /// The number of freed pages that could not be kept track of
+    leaked: usize,
+}
+
+impl RegionAllocator {
+    /// Creates an allocator that manages the `pages` pages starting at `start`
+    pub fn new(start: VirtualAddress, pages: usize) -> RegionAllocator {
+        assert!(start % PAGE_SIZE == 0, "Regions must be page aligned");
+        let mut allocator = RegionAllocator {
+            free: Vec::new(),
+            total: pages,
+            leaked: 0,
+        };
+        allocator.free(Region { start: start, pages: pages });
+        allocator
+    }
+
+    /// Allocates a region of `pages` pages. Returns `None` if there is no
+    /// free range that is large enough.
+    pub fn allocate(&mut self, pages: usize) -> Option<Region> {
+        if pages == 0 {
+            return None;
+        }
+        let index = self.free.iter().position(|range| range.pages >= pages)?;
+        let start = self.free[index].start;
+        self.take(index, start, pages);
+        Some(Region { start: start, pages: pages })
+    }
+
+    /// Allocates the region of `pages` pages starting at `start`. Returns
+    /// `None` if any part of it is not free, or if it would split a free range
+    /// and there is no memory to keep track of another range.
+    pub fn reserve(&mut self, start: VirtualAddress, pages: usize) -> Option<Region> {
+        let end = start + pages * PAGE_SIZE;
+        let index = self.free.iter()
+            .position(|range| range.start <= start && end <= range.end_address())?;
+        let range = self.free[index];
+        if range.start != start && end != range.end_address() &&
+            self.free.try_reserve(1).is_err()
+        {
+            return None;
+        }
+        self.take(index, start, pages);
+        Some(Region { start: start, pages: pages })
+    }
+
+    /// Returns `region` to the allocator, merging it with any adjacent free
+    /// ranges.
+    ///
+    /// If the region cannot be merged and there is no memory to keep track of
+    /// another range, it is leaked instead.
+    pub fn free(&mut self, region: Region) {
+        let index = self.free.iter()
+            .position(|range| range.start > region.start)
+            .unwrap_or(self.free.len());
+
+        let merge_prev = index > 0 && self.free[index - 1].end_address() == region.start;
+        let merge_next = index < self.free.len() && region.end_address() == self.free[index].start;
+
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                let next = self.free.remove(index);
+                self.free[index - 1].pages += region.pages + next.pages;
+            },
+            (true, false) => self.free[index - 1].pages += region.pages,
+            (false, true) => {
+                self.free[index].start = region.start;
+                self.free[index].pages += region.pages;
+            },
+            (false, false) => {
+                if self.free.try_reserve(1).is_ok() {
+                    self.free.insert(index, region);
+                } else {
+                    self.leaked += region.pages;
+                }
+            },
+        }
+    }
+
+    /// Returns the number of free pages
+    pub fn free_pages(&self) -> usize {
+        self.free.iter().map(|range| range.pages).sum()
+    }
+
+    /// Returns the number of pages managed by the allocator
+    pub fn total_pages(&self) -> usize {
+        self.total
+    }
+
+    /// Returns the number of pages that were freed but could not be kept
+    /// track of, so they can never be allocated again
+    pub fn leaked_pages(&self) -> usize {
+        self.leaked
+    }
+
+    /// Returns the size in pages of the largest free range
+    pub fn largest_free(&self) -> usize {
+        self.free.iter().map(|range| range.pages).max().unwrap_or(0)
+    }
+
+    /// Removes the `pages` pages at `start` from the free range at `index`,
+    /// which must contain them. Splitting the range must not need to allocate.
+    fn take(&mut self, index: usize, start: VirtualAddress, pages: usize) {
+        let range = self.free[index];
+        let front = Region {
+            start: range.start,
+            pages: (start - range.start) / PAGE_SIZE,
+        };
+        let back = Region {
+            start: start + pages * PAGE_SIZE,
+            pages: range.pages - front.pages - pages,
+        };
+
+        match (front.pages, back.pages) {
+            (0, 0) => {
+                self.free.remove(index);
+            },
+            (0, _) => self.free[index] = back,
+            (_, 0) => self.free[index] = front,
+            _ => {
+                self.free[index] = front;
+                self.free.insert(index + 1, back);
+            },
+        }
+    }
+}
+
+#[cfg(feature = "test")]
+pub mod tests {
+    use alloc::vec::Vec;
+    use tap::TestGroup;
+    use memory::PAGE_SIZE;
+    use super::RegionAllocator;
+
+    pub fn run() {
+        let mut tap = TestGroup::new(6);
+        tap.diagnostic("Testing the region allocator");
+
+        let start = 0o177777_400_000_000_000_0000;
+        let mut regions = RegionAllocator::new(start, 64);
+
+        let a = regions.allocate(16).expect("Could not allocate region");
+        let b = regions.allocate(16).expect("Could not allocate region");
+        tap.assert_tap(a.start_address() == start && b.start_address() == a.end_address(),
+                       "Regions were not allocated first fit");
+
+        regions.free(a);
+        let c = regions.allocate(8).expect("Could not allocate region");
+        tap.assert_tap(c.start_address() == start, "Freed region was not reused");
+        tap.assert_tap(regions.allocate(40).is_none(), "Region allocated past the end");
+
+        regions.free(b);
+        regions.free(c);
+        tap.assert_tap(regions.free_pages() == 64 && regions.largest_free() == 64,
+                       "Freed regions were not merged");
+
+        let d = regions.reserve(start + 4 * PAGE_SIZE, 4);
+        tap.assert_tap(d.is_some() && regions.reserve(start + 6 * PAGE_SIZE, 4).is_none() &&
+                           regions.largest_free() == 56,
+                       "Could not reserve a fixed region");
+
+        // Split the free space into more ranges than a fixed table would hold
+        let ranges = 1024;
+        let mut regions = RegionAllocator::new(start, 3 * ranges);
+        let reserved: Vec<_> = (0..ranges)
+            .filter_map(|i| regions.reserve(start + (3 * i + 1) * PAGE_SIZE, 1))
+            .collect();
+        let all_reserved = reserved.len() == ranges;
+        for region in reserved {
+            regions.free(region);
+        }
+        tap.assert_tap(all_reserved && regions.free_pages() == 3 * ranges &&
+                           regions.largest_free() == 3 * ranges && regions.leaked_pages() == 0,
+                       "Could not split and merge many free ranges");
+    }
+}
@@ -0,0 +1,402 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Caches for fixed size kernel objects
+//!
+//! Each cache hands out objects of a single type from slabs, which are single
+//! pages of kernel virtual memory mapped from the frame allocator. A slab
+//! starts with a `Slab` header that is followed by as many objects as will fit
+//! in the page. Free objects are kept in a list inside of their slab. Each
+//! cache keeps at most one empty slab, any others are unmapped.
+
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ops::{Deref, DerefMut, Drop};
+use core::ptr::{self, NonNull};
+
+use spin::Once;
+
+use sync::IrqLock;
+use memory::{PAGE_SIZE, FrameAllocate};
+use memory::{MemoryController, MEMORY_CONTROLLER};
+use memory::paging;
+use memory::region_allocator::Region;
+
+/// The most caches that are kept track of for statistics
+const MAX_CACHES: usize = 16;
+
+/// Every cache that has been used, for statistics
+static CACHES: IrqLock<[Option<&'static IrqLock<SlabCache>>; MAX_CACHES]> =
+    IrqLock::new([None; MAX_CACHES]);
+
+/// Maps a new page for a slab. Returns `None` if out of frames or out of
+/// kernel virtual memory.
+fn map_slab_page() -> Option<Region> {
+    let mut lock = MEMORY_CONTROLLER.lock();
+    let &mut MemoryController {
+        ref mut active_table,
+        ref mut frame_allocator,
+        ref mut regions,
+    } = lock.as_mut().unwrap();
+
+    let region = regions.allocate(1)?;
+    let frame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => {
+            regions.free(region);
+            return None;
+        },
+    };
+    active_table.map_to(region.start_page(), frame,
+                        paging::EntryFlags::WRITABLE | paging::EntryFlags::NO_EXECUTE,
+                        frame_allocator)
+        .expect("Slab page is already mapped");
+    Some(region)
+}
+
+/// Unmaps the page of a slab and frees its frame
+fn unmap_slab_page(region: Region) {
+    let mut lock = MEMORY_CONTROLLER.lock();
+    let &mut MemoryController {
+        ref mut active_table,
+        ref mut frame_allocator,
+        ref mut regions,
+    } = lock.as_mut().unwrap();
+
+    active_table.unmap(region.start_page(), frame_allocator);
+    regions.free(region);
+}
+
+/// The header at the start of every slab
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    /// The virtual memory of the slab
+    region: Region,
+    /// The first free object in the slab
+    free: *mut FreeObject,
+    /// The number of objects in use
+    in_use: usize,
+}
+
+/// A free object, which links to the next free object in its slab
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Adds `slab` to the front of `list`
+unsafe fn push(list: &mut *mut Slab, slab: *mut Slab) {
+    (*slab).prev = ptr::null_mut();
+    (*slab).next = *list;
+    if !(*list).is_null() {
+        (**list).prev = slab;
+    }
+    *list = slab;
+}
+
+/// Removes `slab` from `list`
+unsafe fn remove(list: &mut *mut Slab, slab: *mut Slab) {
+    if (*slab).prev.is_null() {
+        *list = (*slab).next;
+    } else {
+        (*(*slab).prev).next = (*slab).next;
+    }
+    if !(*slab).next.is_null() {
+        (*(*slab).next).prev = (*slab).prev;
+    }
+}
+
+/// Statistics for a single cache
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    /// The size of each object, including padding
+    pub object_size: usize,
+    /// The number of slabs (pages) in the cache
+    pub slabs: usize,
+    /// The number of objects that are allocated
+    pub in_use: usize,
+    /// The number of objects that can be allocated without a new slab
+    pub free: usize,
+}
+
+impl fmt::Display for SlabStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} in use, {} free ({} slabs, {} byte objects)",
+               self.name, self.in_use, self.free, self.slabs, self.object_size)
+    }
+}
+
+/// An untyped cache of objects
+pub struct SlabCache {
+    name: &'static str,
+    /// The size of each object, including padding
+    size: usize,
+    /// The offset of the first object in a slab
+    offset: usize,
+    /// The number of objects in each slab
+    per_slab: usize,
+    /// Slabs with both used and free objects
+    partial: *mut Slab,
+    /// Slabs without free objects
+    full: *mut Slab,
+    /// Slabs without used objects. There is at most one.
+    empty: *mut Slab,
+    slabs: usize,
+    in_use: usize,
+}
+
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    const fn new(name: &'static str) -> SlabCache {
+        SlabCache {
+            name: name,
+            size: 0,
+            offset: 0,
+            per_slab: 0,
+            partial: 0 as *mut Slab,
+            full: 0 as *mut Slab,
+            empty: 0 as *mut Slab,
+            slabs: 0,
+            in_use: 0,
+        }
+    }
+
+    /// Sets the size and alignment of the objects in the cache
+    fn configure(&mut self, size: usize, align: usize) {
+        let align = align.max(align_of::<FreeObject>());
+        self.size = align_up(size.max(size_of::<FreeObject>()), align);
+        self.offset = align_up(size_of::<Slab>(), align);
+        assert!(self.offset + self.size <= PAGE_SIZE,
+                "Objects in cache {} are too large for a slab", self.name);
+        self.per_slab = (PAGE_SIZE - self.offset) / self.size;
+    }
+
+    /// Maps a new slab and adds it to the empty list
+    fn grow(&mut self) -> Option<()> {
+        let region = map_slab_page()?;
+        let slab = region.start_address() as *mut Slab;
+        unsafe {
+            // Link every object into the free list
+            let first = region.start_address() + self.offset;
+            for i in 0..self.per_slab {
+                let object = (first + i * self.size) as *mut FreeObject;
+                (*object).next = if i + 1 < self.per_slab {
+                    (first + (i + 1) * self.size) as *mut FreeObject
+                } else {
+                    ptr::null_mut()
+                };
+            }
+            ptr::write(slab, Slab {
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                region: region,
+                free: first as *mut FreeObject,
+                in_use: 0,
+            });
+            push(&mut self.empty, slab);
+        }
+        self.slabs += 1;
+        Some(())
+    }
+
+    /// Allocates an object from the cache
+    fn alloc(&mut self) -> Option<NonNull<u8>> {
+        unsafe {
+            if self.partial.is_null() {
+                if self.empty.is_null() {
+                    self.grow()?;
+                }
+                let slab = self.empty;
+                remove(&mut self.empty, slab);
+                push(&mut self.partial, slab);
+            }
+
+            let slab = self.partial;
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                remove(&mut self.partial, slab);
+                push(&mut self.full, slab);
+            }
+            self.in_use += 1;
+            NonNull::new(object as *mut u8)
+        }
+    }
+
+    /// Returns an object to the cache
+    ///
+    /// # Safety
+    /// `object` must have been allocated from this cache
+    unsafe fn free(&mut self, object: NonNull<u8>) {
+        let slab = (object.as_ptr() as usize & !(PAGE_SIZE - 1)) as *mut Slab;
+        let object = object.as_ptr() as *mut FreeObject;
+
+        let was_full = (*slab).free.is_null();
+        (*object).next = (*slab).free;
+        (*slab).free = object;
+        (*slab).in_use -= 1;
+        self.in_use -= 1;
+
+        if was_full {
+            remove(&mut self.full, slab);
+            push(&mut self.partial, slab);
+        }
+        if (*slab).in_use == 0 {
+            remove(&mut self.partial, slab);
+            if self.empty.is_null() {
+                push(&mut self.empty, slab);
+            } else {
+                // Keep one empty slab so that a single object being
+                // allocated and freed does not map and unmap a page each time
+                unmap_slab_page((*slab).region);
+                self.slabs -= 1;
+            }
+        }
+    }
+
+    fn stats(&self) -> SlabStats {
+        SlabStats {
+            name: self.name,
+            object_size: self.size,
+            slabs: self.slabs,
+            in_use: self.in_use,
+            free: self.slabs * self.per_slab - self.in_use,
+        }
+    }
+}
+
+/// A cache of objects of type `T`
+pub struct ObjectCache<T> {
+    cache: IrqLock<SlabCache>,
+    init: Once<()>,
+    marker: PhantomData<T>,
+}
+
+// Objects are only ever moved in and out of the cache
+unsafe impl<T: Send> Sync for ObjectCache<T> {}
+
+impl<T> ObjectCache<T> {
+    /// Creates a new cache, `name` is used in its statistics
+    pub const fn new(name: &'static str) -> ObjectCache<T> {
+        ObjectCache {
+            cache: IrqLock::new(SlabCache::new(name)),
+            init: Once::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Moves `value` into an object from the cache. Returns `None` if a new
+    /// slab is needed and cannot be mapped.
+    pub fn alloc(&'static self, value: T) -> Option<SlabBox<T>> {
+        self.init.call_once(|| {
+            self.cache.lock().configure(size_of::<T>(), align_of::<T>());
+            let mut caches = CACHES.lock();
+            if let Some(slot) = caches.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(&self.cache);
+            }
+        });
+
+        let object = self.cache.lock().alloc()?.cast::<T>();
+        unsafe {
+            ptr::write(object.as_ptr(), value);
+        }
+        Some(SlabBox {
+            object: object,
+            cache: self,
+        })
+    }
+
+    /// Returns the statistics of the cache
+    pub fn stats(&self) -> SlabStats {
+        self.cache.lock().stats()
+    }
+}
+
+/// Calls `f` with the statistics of every cache that has been used
+pub fn for_each_cache<F>(mut f: F)
+    where F: FnMut(SlabStats)
+{
+    for cache in CACHES.lock().iter().filter_map(|cache| *cache) {
+        f(cache.lock().stats());
+    }
+}
+
+/// An owned object from an `ObjectCache`, which is returned to the cache
+/// when dropped.
+pub struct SlabBox<T: 'static> {
+    object: NonNull<T>,
+    cache: &'static ObjectCache<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.object.as_ptr());
+            self.cache.cache.lock().free(self.object.cast());
+        }
+    }
+}
+
+/// Align upwards. Returns the smallest x with alignment `align`
+/// such that x >= addr. The alignment must be a power of 2.
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+#[cfg(feature = "test")]
+pub mod tests {
+    use alloc::vec::Vec;
+    use tap::TestGroup;
+    use super::ObjectCache;
+
+    static TEST_CACHE: ObjectCache<[u64; 4]> = ObjectCache::new("test");
+
+    pub fn run() {
+        let mut tap = TestGroup::new(4);
+        tap.diagnostic("Testing slab caches");
+
+        let count = 300;
+        let objects: Vec<_> = (0..count)
+            .filter_map(|i| TEST_CACHE.alloc([i; 4]))
+            .collect();
+        tap.assert_tap(objects.len() == count as usize,
+                       "Could not allocate objects from a slab cache");
+        tap.assert_tap(objects.iter().enumerate().all(|(i, o)| **o == [i as u64; 4]),
+                       "Slab objects overlap");
+
+        let stats = TEST_CACHE.stats();
+        tap.assert_tap(stats.in_use == count as usize && stats.slabs > 1,
+                       "Slab statistics are wrong while objects are in use");
+        drop(objects);
+
+        let stats = TEST_CACHE.stats();
+        tap.assert_tap(stats.in_use == 0 && stats.slabs == 1,
+                       "Empty slabs were not released");
+    }
+}
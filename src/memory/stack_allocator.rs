@@ -7,19 +7,58 @@
 // This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use memory::{PAGE_SIZE, FrameAllocate};
-use memory::paging::{self, Page, PageIter, ActivePageTable};
+use memory::{PAGE_SIZE, FrameAllocate, FrameDeallocate};
+use memory::{MemoryController, MEMORY_CONTROLLER};
+use memory::fault;
+use memory::paging::{self, Page, ActivePageTable};
+use memory::region_allocator::{Region, RegionAllocator};
 use core::ops::Drop;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// The largest stack that can be allocated, in pages
+pub const MAX_STACK_PAGES: usize = 256;
+/// The number of pages at the top of a stack that are mapped when it is
+/// allocated. The rest of a larger stack is lazily backed.
+pub const EAGER_STACK_PAGES: usize = 8;
+
+/// The number of stacks owned by the stack allocator
+static STACKS: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The number of pages used by those stacks, not counting guard pages
+static STACK_PAGES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Returns the number of stacks that have been allocated and not freed, and
+/// the number of pages that they use.
+pub fn usage() -> (usize, usize) {
+    (STACKS.load(Ordering::Relaxed), STACK_PAGES.load(Ordering::Relaxed))
+}
+
+/// The reason a stack could not be allocated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    /// The stack size in pages was zero or larger than `MAX_STACK_PAGES`
+    InvalidSize(usize),
+    /// There was no free range of kernel virtual memory large enough
+    OutOfVirtualMemory,
+    /// There were not enough free frames to map the stack
+    OutOfFrames,
+}
 
 #[derive(Debug)]
 pub struct Stack {
     top: usize,
     bottom: usize,
+    /// The region the stack was allocated from, including its guard page.
+    /// `None` if the stack is not owned by the stack allocator.
+    region: Option<Region>,
+    /// The number of pages at the bottom of the stack that are lazily backed
+    lazy_pages: usize,
 }
 
 impl Stack {
     /// Create a new stack with the given top and bottom
     ///
+    /// The stack is never freed.
+    ///
     /// # Safety
     /// Top and bottom must be page aligned valid addresses that are not
     /// already used
@@ -29,6 +68,8 @@ impl Stack {
         Stack {
             top: top,
             bottom: bottom,
+            region: None,
+            lazy_pages: 0,
         }
     }
 
@@ -39,62 +80,106 @@ impl Stack {
     pub fn bottom(&self) -> usize {
         self.bottom
     }
-}
 
-pub struct StackAllocator {
-    range: PageIter,
-}
+    /// Returns the lowest address of the stack that was mapped when it was
+    /// allocated. Pages below it are mapped when they are first touched.
+    pub fn mapped_bottom(&self) -> usize {
+        self.bottom + self.lazy_pages * PAGE_SIZE
+    }
 
-impl StackAllocator {
-    pub fn new(page_range: PageIter) -> StackAllocator {
-        StackAllocator { range: page_range }
+    /// Returns true if `address` is in the unmapped guard page below the
+    /// stack. Stacks not owned by the stack allocator have no guard page.
+    pub fn in_guard_page(&self, address: usize) -> bool {
+        self.region.map_or(false, |region| {
+            region.start_address() <= address && address < self.bottom
+        })
     }
+}
 
-    /// Create a stack of `PAGE_SIZE * size` bytes
-    pub fn alloc_stack<FA>(&mut self,
-                           active_table: &mut ActivePageTable,
-                           allocator: &mut FA,
-                           size: usize) -> Result<Stack, &'static str>
-        where FA: FrameAllocate
-    {
-        // Only mutate in success
-        let mut range = self.range.clone();
-
-        let guard_page = range.next();
-        let stack_start = range.next();
-        let stack_end = match size {
-            0 => return Err("Stack is zero sized"), /* Don't do anything for a zero sized stack */
-            1 => stack_start,
-            n => range.nth(n - 2),
-        };
+/// Create a stack of `PAGE_SIZE * size` bytes, with an unmapped guard page
+/// below it.
+///
+/// Only the top `EAGER_STACK_PAGES` pages are mapped, the rest are mapped by
+/// the page fault handler when they are first touched.
+pub fn alloc_stack<FA>(active_table: &mut ActivePageTable,
+                       allocator: &mut FA,
+                       regions: &mut RegionAllocator,
+                       size: usize) -> Result<Stack, StackError>
+    where FA: FrameAllocate + FrameDeallocate
+{
+    if size == 0 || size > MAX_STACK_PAGES {
+        return Err(StackError::InvalidSize(size));
+    }
+    let region = regions.allocate(size + 1).ok_or(StackError::OutOfVirtualMemory)?;
 
-        match (guard_page, stack_start, stack_end) {
-            (Some(_), Some(start), Some(end)) => {
-                // Success, mutate and return
-                self.range = range;
+    // Skip the guard page
+    let start = region.start_page() + 1;
+    let end = start + (size - 1);
+    let flags = paging::EntryFlags::WRITABLE | paging::EntryFlags::NO_EXECUTE;
 
-                // Map to physical pages
-                for page in Page::range_inclusive(start, end) {
-                    active_table.map(page, paging::EntryFlags::WRITABLE, allocator);
+    // The stack grows downward, so its top is always used
+    let lazy_pages = size.saturating_sub(EAGER_STACK_PAGES);
+    let eager = Page::range_inclusive(start + lazy_pages, end);
+    for page in eager.clone() {
+        let frame = match allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => {
+                // Undo everything that has been mapped so far
+                for mapped in eager.take_while(|&mapped| mapped < page) {
+                    active_table.unmap(mapped, allocator);
                 }
-
-                // Create stack and return
-                //
-                // The stack grows downward
-                let stack_top = end.start_address() + PAGE_SIZE;
-                let stack_bottom = start.start_address();
-
-                Ok(unsafe { Stack::new(stack_top, stack_bottom) })
+                regions.free(region);
+                return Err(StackError::OutOfFrames);
             },
-            _ => Err("Not enough pages in the stack allocator!"), /* Not enough pages */
-        }
+        };
+        active_table.map_to(page, frame, flags, allocator)
+            .expect("Stack region is already mapped");
+    }
+    if lazy_pages > 0 {
+        fault::register_lazy(start.start_address(), lazy_pages, flags)
+            .expect("Stack region is already lazily backed");
     }
+
+    STACKS.fetch_add(1, Ordering::Relaxed);
+    STACK_PAGES.fetch_add(size, Ordering::Relaxed);
+
+    Ok(Stack {
+        top: end.start_address() + PAGE_SIZE,
+        bottom: start.start_address(),
+        region: Some(region),
+        lazy_pages: lazy_pages,
+    })
 }
+
 impl Drop for Stack {
     /// Free the `Stack`'s pages back to the PMM
     fn drop(&mut self) {
-        Page::range_inclusive(Page::containing_address(self.top),
-                              Page::containing_address(self.bottom))
-            .for_each(|x| x.unmap());
+        let region = match self.region.take() {
+            Some(region) => region,
+            None => return,
+        };
+        if self.lazy_pages > 0 {
+            fault::unregister_lazy(self.bottom).expect("Lazy stack was not registered");
+        }
+
+        let mut lock = MEMORY_CONTROLLER.lock();
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut regions,
+        } = lock.as_mut().unwrap();
+
+        let start = Page::containing_address(self.bottom);
+        let end = Page::containing_address(self.top - 1);
+        for page in Page::range_inclusive(start, end) {
+            // Lazily backed pages that were never touched are not mapped
+            if page >= start + self.lazy_pages || active_table.translate_page(page).is_some() {
+                active_table.unmap(page, frame_allocator);
+            }
+        }
+        regions.free(region);
+
+        STACKS.fetch_sub(1, Ordering::Relaxed);
+        STACK_PAGES.fetch_sub(region.pages() - 1, Ordering::Relaxed);
     }
 }
@@ -0,0 +1,198 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Memory usage statistics
+
+use core::alloc::Layout;
+use core::fmt;
+
+use hole_list_allocator::{self, HeapStats};
+
+use memory::{PAGE_SIZE, MemoryController, MEMORY_CONTROLLER};
+use memory::zone::{Zone, ZONE_COUNT};
+use memory::stack_allocator;
+use memory::paging;
+
+/// A snapshot of memory usage
+#[derive(Debug, Clone, Copy)]
+pub struct MemInfo {
+    /// The free frames in each zone, indexed by `Zone`
+    pub free_frames: [usize; ZONE_COUNT],
+    /// The frames given to the frame allocator in each zone, indexed by `Zone`
+    pub total_frames: [usize; ZONE_COUNT],
+    /// The frames used by page tables
+    pub table_frames: usize,
+    /// Usage of the kernel heap
+    pub heap: HeapStats,
+    /// The number of stacks from the stack allocator
+    pub stacks: usize,
+    /// The pages used by those stacks
+    pub stack_pages: usize,
+    /// The free pages of kernel virtual memory handed out in regions
+    pub free_region_pages: usize,
+    /// All pages of kernel virtual memory handed out in regions
+    pub total_region_pages: usize,
+    /// The most pages that can be handed out in a single region
+    pub largest_free_region: usize,
+}
+
+impl MemInfo {
+    fn new(controller: &MemoryController, heap: HeapStats) -> MemInfo {
+        let mut free_frames = [0; ZONE_COUNT];
+        let mut total_frames = [0; ZONE_COUNT];
+        for &zone in Zone::all().iter() {
+            free_frames[zone as usize] = controller.frame_allocator.free_frames_in(zone);
+            total_frames[zone as usize] = controller.frame_allocator.total_frames_in(zone);
+        }
+        let (stacks, stack_pages) = stack_allocator::usage();
+
+        MemInfo {
+            free_frames: free_frames,
+            total_frames: total_frames,
+            table_frames: paging::table_frames(),
+            heap: heap,
+            stacks: stacks,
+            stack_pages: stack_pages,
+            free_region_pages: controller.regions.free_pages(),
+            total_region_pages: controller.regions.total_pages(),
+            largest_free_region: controller.regions.largest_free(),
+        }
+    }
+
+    /// Returns the number of free frames in every zone
+    pub fn free(&self) -> usize {
+        self.free_frames.iter().sum()
+    }
+
+    /// Returns the number of frames in every zone
+    pub fn total(&self) -> usize {
+        self.total_frames.iter().sum()
+    }
+
+    /// Returns how much of the free heap is not in the largest free block, as
+    /// a percentage
+    pub fn heap_fragmentation(&self) -> usize {
+        if self.heap.free == 0 {
+            0
+        } else {
+            100 - self.heap.largest_free * 100 / self.heap.free
+        }
+    }
+}
+
+impl fmt::Display for MemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kib = |frames: usize| frames * PAGE_SIZE / 1024;
+
+        writeln!(f, "Memory:      {} KiB free of {} KiB", kib(self.free()), kib(self.total()))?;
+        for &zone in Zone::all().iter() {
+            writeln!(f, "  {:<10} {} KiB free of {} KiB", format_args!("{:?}:", zone),
+                     kib(self.free_frames[zone as usize]),
+                     kib(self.total_frames[zone as usize]))?;
+        }
+        writeln!(f, "Page tables: {} KiB", kib(self.table_frames))?;
+        writeln!(f, "Heap:        {} bytes free of {} bytes in {} holes ({}% fragmented)",
+                 self.heap.free, self.heap.size, self.heap.holes, self.heap_fragmentation())?;
+        writeln!(f, "Stacks:      {} using {} KiB", self.stacks, kib(self.stack_pages))?;
+        writeln!(f, "Regions:     {} KiB free of {} KiB, largest {} KiB",
+                 kib(self.free_region_pages), kib(self.total_region_pages),
+                 kib(self.largest_free_region))
+    }
+}
+
+/// Returns the current memory usage
+pub fn stats() -> MemInfo {
+    // The heap locks the memory controller when it grows, so it must be
+    // queried first
+    let heap = hole_list_allocator::stats();
+    let lock = MEMORY_CONTROLLER.lock();
+    MemInfo::new(lock.as_ref().unwrap(), heap)
+}
+
+/// Prints the current memory usage to the screen, and over serial as TAP
+/// diagnostics
+pub fn print_stats() {
+    use core::fmt::Write;
+    use cpuio;
+
+    let info = stats();
+    println!("{}", info);
+    if let Some(mut serial) = cpuio::COM1.try_lock() {
+        let _ = write!(Diagnostic { inner: &mut *serial, line_start: true }, "{}", info);
+    }
+}
+
+/// Prefixes every line written to `inner` with `# `
+struct Diagnostic<'a, W: fmt::Write + 'a> {
+    inner: &'a mut W,
+    line_start: bool,
+}
+
+impl<'a, W: fmt::Write> fmt::Write for Diagnostic<'a, W> {
+    fn write_str(&mut self, mut s: &str) -> fmt::Result {
+        while !s.is_empty() {
+            if self.line_start {
+                self.inner.write_str("# ")?;
+            }
+            let end = s.find('\n').map_or(s.len(), |i| i + 1);
+            self.inner.write_str(&s[..end])?;
+            self.line_start = s[..end].ends_with('\n');
+            s = &s[end..];
+        }
+        Ok(())
+    }
+}
+
+/// Writes a report of memory usage to `w` after an allocation of `layout`
+/// failed
+pub fn write_oom_report<W: fmt::Write>(w: &mut W, layout: Layout) -> fmt::Result {
+    writeln!(w, "Out of memory allocating {} bytes aligned to {}",
+             layout.size(), layout.align())?;
+
+    let heap = hole_list_allocator::stats();
+    // The allocation may have failed with the lock held
+    match MEMORY_CONTROLLER.try_lock() {
+        Some(lock) => write!(w, "{}", MemInfo::new(lock.as_ref().unwrap(), heap)),
+        None => {
+            writeln!(w, "Heap: {} bytes free of {} bytes, largest block {} bytes",
+                     heap.free, heap.size, heap.largest_free)?;
+            writeln!(w, "Frame usage is unavailable while memory is locked")
+        },
+    }
+}
+
+#[cfg(feature = "test")]
+pub mod tests {
+    use tap::TestGroup;
+    use memory::{PAGE_SIZE, alloc_stack};
+    use memory::vmalloc::{vmalloc, vfree, Guard};
+    use super::stats;
+
+    pub fn run() {
+        let mut tap = TestGroup::new(3);
+        tap.diagnostic("Testing memory statistics");
+
+        let before = stats();
+        tap.assert_tap(before.free() <= before.total() && before.table_frames > 0,
+                       "Frame statistics are inconsistent");
+
+        let area = vmalloc(16 * PAGE_SIZE, Guard::empty()).expect("Could not vmalloc");
+        let stack = alloc_stack(2).expect("Could not allocate a stack");
+        let during = stats();
+        tap.assert_tap(during.free() + 18 <= before.free() &&
+                           during.free_region_pages + 19 <= before.free_region_pages,
+                       "Allocations were not counted");
+        tap.assert_tap(during.stacks == before.stacks + 1 &&
+                           during.stack_pages == before.stack_pages + 2,
+                       "Stack was not counted");
+
+        drop(stack);
+        vfree(area);
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Virtually contiguous allocations
+//!
+//! Each allocation reserves a kernel region and backs every page of it with a
+//! separately allocated frame, so large buffers do not need physically
+//! contiguous memory.
+
+use memory::{PAGE_SIZE, FrameAllocate};
+use memory::{MemoryController, MEMORY_CONTROLLER};
+use memory::paging::{self, Page, VirtualAddress};
+use memory::region_allocator::Region;
+use memory::fault;
+
+bitflags! {
+    /// Where unmapped guard pages are placed around an allocation
+    pub struct Guard: u8 {
+        /// A guard page below the allocation
+        const BEFORE = 1 << 0;
+        /// A guard page above the allocation
+        const AFTER =  1 << 1;
+    }
+}
+
+/// A virtually contiguous allocation from `vmalloc`
+#[derive(Debug)]
+#[must_use = "A VmArea must be freed with `vfree`"]
+pub struct VmArea {
+    /// The region of the allocation, including its guard pages
+    region: Region,
+    start: VirtualAddress,
+    pages: usize,
+    /// If set, pages are only mapped when they are first touched
+    lazy: bool,
+}
+
+impl VmArea {
+    /// Returns the first address of the allocation
+    pub fn start_address(&self) -> VirtualAddress {
+        self.start
+    }
+
+    /// Returns the size of the allocation in bytes
+    pub fn size(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
+    /// Returns a pointer to the start of the allocation
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.start as *mut u8
+    }
+}
+
+/// Allocates at least `size` bytes of virtually contiguous memory, with guard
+/// pages as given by `guard`. Returns `None` if out of frames or kernel
+/// virtual memory.
+pub fn vmalloc(size: usize, guard: Guard) -> Option<VmArea> {
+    if size == 0 {
+        return None;
+    }
+    let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let before = if guard.contains(Guard::BEFORE) { 1 } else { 0 };
+    let after = if guard.contains(Guard::AFTER) { 1 } else { 0 };
+
+    let mut lock = MEMORY_CONTROLLER.lock();
+    let &mut MemoryController {
+        ref mut active_table,
+        ref mut frame_allocator,
+        ref mut regions,
+    } = lock.as_mut().unwrap();
+
+    let region = regions.allocate(before + pages + after)?;
+    let start_page = region.start_page() + before;
+
+    for i in 0..pages {
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => {
+                // Undo everything that has been mapped so far
+                for page in (0..i).map(|j| start_page + j) {
+                    active_table.unmap(page, frame_allocator);
+                }
+                regions.free(region);
+                return None;
+            },
+        };
+        active_table.map_to(start_page + i, frame, paging::EntryFlags::WRITABLE |
+                            paging::EntryFlags::NO_EXECUTE, frame_allocator)
+            .expect("vmalloc region is already mapped");
+    }
+
+    Some(VmArea {
+        region: region,
+        start: start_page.start_address(),
+        pages: pages,
+        lazy: false,
+    })
+}
+
+/// Like `vmalloc`, but only reserves the memory. Each page is mapped to a
+/// zeroed frame when it is first touched.
+pub fn vmalloc_lazy(size: usize, guard: Guard) -> Option<VmArea> {
+    if size == 0 {
+        return None;
+    }
+    let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let before = if guard.contains(Guard::BEFORE) { 1 } else { 0 };
+    let after = if guard.contains(Guard::AFTER) { 1 } else { 0 };
+
+    let region = {
+        let mut lock = MEMORY_CONTROLLER.lock();
+        lock.as_mut().unwrap().regions.allocate(before + pages + after)?
+    };
+    let start = region.start_address() + before * PAGE_SIZE;
+
+    let flags = paging::EntryFlags::WRITABLE | paging::EntryFlags::NO_EXECUTE;
+    if fault::register_lazy(start, pages, flags).is_err() {
+        MEMORY_CONTROLLER.lock().as_mut().unwrap().regions.free(region);
+        return None;
+    }
+
+    Some(VmArea {
+        region: region,
+        start: start,
+        pages: pages,
+        lazy: true,
+    })
+}
+
+/// Unmaps and frees an allocation from `vmalloc`
+pub fn vfree(area: VmArea) {
+    if area.lazy {
+        fault::unregister_lazy(area.start).expect("Lazy vmalloc area was not registered");
+    }
+
+    let mut lock = MEMORY_CONTROLLER.lock();
+    let &mut MemoryController {
+        ref mut active_table,
+        ref mut frame_allocator,
+        ref mut regions,
+    } = lock.as_mut().unwrap();
+
+    let start = Page::containing_address(area.start);
+    for page in Page::range_inclusive(start, start + (area.pages - 1)) {
+        // Untouched pages of lazy areas were never mapped
+        if !area.lazy || active_table.translate_page(page).is_some() {
+            active_table.unmap(page, frame_allocator);
+        }
+    }
+    regions.free(area.region);
+}
+
+#[cfg(feature = "test")]
+pub mod tests {
+    use tap::TestGroup;
+    use memory::{PAGE_SIZE, MEMORY_CONTROLLER};
+    use super::{vmalloc, vfree, Guard};
+
+    fn free_frames() -> usize {
+        MEMORY_CONTROLLER.lock().as_ref().unwrap().frame_allocator.free_frames()
+    }
+
+    fn is_mapped(address: usize) -> bool {
+        MEMORY_CONTROLLER.lock().as_ref().unwrap().active_table.translate(address).is_some()
+    }
+
+    pub fn run() {
+        let mut tap = TestGroup::new(5);
+        tap.diagnostic("Testing vmalloc");
+
+        let free = free_frames();
+        let size = 1024 * 1024; // 1MiB
+        let area = vmalloc(size, Guard::BEFORE | Guard::AFTER)
+            .expect("Could not vmalloc 1MiB");
+        tap.assert_tap(area.size() == size, "vmalloc area has the wrong size");
+
+        let start = area.start_address();
+        unsafe {
+            for offset in (0..size / PAGE_SIZE).map(|page| page * PAGE_SIZE) {
+                *area.as_mut_ptr().offset(offset as isize) = 0xa5;
+            }
+        }
+        let written = (0..size / PAGE_SIZE)
+            .all(|page| unsafe { *area.as_mut_ptr().offset((page * PAGE_SIZE) as isize) } == 0xa5);
+        tap.assert_tap(written, "Could not access every page of a vmalloc area");
+        tap.assert_tap(!is_mapped(start - PAGE_SIZE) && !is_mapped(start + size),
+                       "vmalloc guard pages are mapped");
+
+        vfree(area);
+        tap.assert_tap(!is_mapped(start) && free_frames() == free,
+                       "vfree did not free every frame");
+
+        let area = vmalloc(size, Guard::BEFORE | Guard::AFTER)
+            .expect("Could not vmalloc 1MiB");
+        tap.assert_tap(area.start_address() == start, "vmalloc region was not reused");
+        vfree(area);
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use memory::{PAGE_SIZE, PhysicalAddress};
+
+/// The number of zones
+pub const ZONE_COUNT: usize = 3;
+
+/// A range of physical memory that some devices are restricted to.
+///
+/// The boundaries of each zone are aligned to the largest buddy block, so no
+/// block is ever split across two zones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Memory below 16MiB, for legacy ISA DMA
+    Dma = 0,
+    /// Memory below 4GiB, for devices that can only address 32 bits
+    Dma32 = 1,
+    /// All other memory
+    Normal = 2,
+}
+
+/// The end of the `Dma` zone
+const DMA_END: PhysicalAddress = 16 * 1024 * 1024;
+/// The end of the `Dma32` zone
+const DMA32_END: PhysicalAddress = 4 * 1024 * 1024 * 1024;
+
+impl Zone {
+    /// Returns every zone, from lowest to highest
+    pub fn all() -> [Zone; ZONE_COUNT] {
+        [Zone::Dma, Zone::Dma32, Zone::Normal]
+    }
+
+    /// Returns the zone that contains the frame with the given number
+    pub fn containing(frame: usize) -> Zone {
+        match frame * PAGE_SIZE {
+            addr if addr < DMA_END => Zone::Dma,
+            addr if addr < DMA32_END => Zone::Dma32,
+            _ => Zone::Normal,
+        }
+    }
+
+    /// Returns the frame numbers in the zone as `(start, end)`, where `end`
+    /// is exclusive.
+    pub fn frames(self) -> (usize, usize) {
+        match self {
+            Zone::Dma => (0, DMA_END / PAGE_SIZE),
+            Zone::Dma32 => (DMA_END / PAGE_SIZE, DMA32_END / PAGE_SIZE),
+            Zone::Normal => (DMA32_END / PAGE_SIZE, !0 / PAGE_SIZE),
+        }
+    }
+
+    /// Returns the zones that may be used when an allocation from this zone
+    /// cannot be satisfied, in the order they should be tried. Lower zones
+    /// can always stand in for higher ones, but not the other way around.
+    pub fn fallback(self) -> &'static [Zone] {
+        match self {
+            Zone::Dma => &[Zone::Dma],
+            Zone::Dma32 => &[Zone::Dma32, Zone::Dma],
+            Zone::Normal => &[Zone::Normal, Zone::Dma32, Zone::Dma],
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Configuring new threads
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt;
+
+use memory::{AddressSpace, StackError, MAX_STACK_PAGES};
+
+use super::{JoinHandle, Priority, queue};
+use super::thread::KThread;
+
+/// The size of a thread's stack if none is given, in pages
+pub const DEFAULT_STACK_PAGES: usize = 4;
+
+/// The reason a thread could not be created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The stack size in pages was zero or larger than `MAX_STACK_PAGES`
+    InvalidStackSize(usize),
+    /// There was no free range of kernel virtual memory for the stack
+    OutOfVirtualMemory,
+    /// There were not enough free frames to map the stack
+    OutOfFrames,
+    /// The thread control block or the scheduler's queues could not be grown
+    OutOfMemory,
+}
+
+impl From<StackError> for SpawnError {
+    fn from(error: StackError) -> SpawnError {
+        match error {
+            StackError::InvalidSize(pages) => SpawnError::InvalidStackSize(pages),
+            StackError::OutOfVirtualMemory => SpawnError::OutOfVirtualMemory,
+            StackError::OutOfFrames => SpawnError::OutOfFrames,
+        }
+    }
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SpawnError::InvalidStackSize(pages) =>
+                write!(f, "A stack of {} pages is not between 1 and {} pages",
+                       pages, MAX_STACK_PAGES),
+            SpawnError::OutOfVirtualMemory =>
+                write!(f, "Not enough virtual memory for the stack"),
+            SpawnError::OutOfFrames => write!(f, "Not enough frames for the stack"),
+            SpawnError::OutOfMemory => write!(f, "Not enough memory for the thread"),
+        }
+    }
+}
+
+/// Sets up the properties of a new thread before it is spawned
+pub struct Builder {
+    name: Option<String>,
+    stack_pages: usize,
+    priority: Priority,
+    address_space: Option<Arc<AddressSpace>>,
+}
+
+impl Builder {
+    /// Returns a builder for an unnamed thread of normal priority with the
+    /// default stack size. Threads run on the CPU that spawns them.
+    pub fn new() -> Builder {
+        Builder {
+            name: None,
+            stack_pages: DEFAULT_STACK_PAGES,
+            priority: Priority::Normal,
+            address_space: None,
+        }
+    }
+
+    /// Names the thread
+    pub fn name<S: Into<String>>(mut self, name: S) -> Builder {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the size of the thread's stack in pages
+    pub fn stack_pages(mut self, pages: usize) -> Builder {
+        self.stack_pages = pages;
+        self
+    }
+
+    /// Sets the priority the thread starts with
+    pub fn priority(mut self, priority: Priority) -> Builder {
+        self.priority = priority;
+        self
+    }
+
+    /// Runs the thread in `space` instead of the kernel's address space
+    pub fn address_space(mut self, space: Arc<AddressSpace>) -> Builder {
+        self.address_space = Some(space);
+        self
+    }
+
+    /// Creates a thread that runs the closure `f`. The value returned by `f`
+    /// is the thread's exit status.
+    ///
+    /// The closure and everything it captures are dropped when it returns.
+    /// If the thread exits any other way they are leaked.
+    pub fn spawn<F>(self, f: F) -> Result<JoinHandle, SpawnError>
+        where F: FnOnce() -> usize + Send + 'static
+    {
+        if self.stack_pages == 0 || self.stack_pages > MAX_STACK_PAGES {
+            return Err(SpawnError::InvalidStackSize(self.stack_pages));
+        }
+
+        let data = Box::into_raw(Box::new(f)) as usize;
+        let result = KThread::new(trampoline::<F>, data, self.priority, self.stack_pages)
+            .and_then(|mut thread| {
+                thread.name = self.name.map(Arc::from);
+                thread.address_space = self.address_space;
+                queue(thread)
+            });
+        if result.is_err() {
+            // The thread will never run, so the closure is still ours
+            drop(unsafe { Box::from_raw(data as *mut F) });
+        }
+        result
+    }
+}
+
+/// The start of every spawned thread. Takes ownership of the boxed closure at
+/// `data` and runs it.
+extern "C" fn trampoline<F>(data: usize) -> usize
+    where F: FnOnce() -> usize
+{
+    let f = unsafe { *Box::from_raw(data as *mut F) };
+    f()
+}
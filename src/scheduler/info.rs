@@ -0,0 +1,179 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Thread introspection
+//!
+//! `threads` takes a snapshot of every thread on the current CPU, and
+//! `print_threads` lists them over serial like `ps`.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+use smp::current;
+
+use super::{Priority, State, Scheduler};
+use super::thread::KThread;
+
+/// The header of the table printed by `print_threads`
+const HEADER: &'static str =
+    "   ID NAME             STATE     PRIORITY    TICKS SWITCHES STACK KiB";
+
+/// A snapshot of one thread
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub id: usize,
+    pub name: Option<Arc<str>>,
+    pub state: State,
+    pub priority: Priority,
+    /// The number of ticks the thread has been running for
+    pub ticks: usize,
+    /// The number of times the thread has been switched to
+    pub switches: usize,
+    /// The most bytes of its stack the thread has used, if it is known
+    pub stack_used: Option<usize>,
+    /// The size of the thread's stack in bytes
+    pub stack_size: usize,
+}
+
+impl ThreadInfo {
+    fn new(thread: &KThread) -> ThreadInfo {
+        ThreadInfo {
+            id: thread.id,
+            name: thread.name.clone(),
+            state: thread.state,
+            priority: thread.priority,
+            ticks: thread.ticks,
+            switches: thread.switches,
+            stack_used: thread.stack_high_water(),
+            stack_size: thread.stack_size(),
+        }
+    }
+}
+
+impl fmt::Display for ThreadInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self.state {
+            State::Running => "running",
+            State::Ready => "ready",
+            State::Sleeping => "sleeping",
+            State::Joining(_) => "joining",
+        };
+        let priority = match self.priority {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        };
+        write!(f, "{:5} {:16} {:9} {:8} {:8} {:8} ",
+               self.id, self.name.as_ref().map_or("-", |name| &**name), state,
+               priority, self.ticks, self.switches)?;
+        match self.stack_used {
+            Some(used) => write!(f, "{:>4}/{}", used / 1024, self.stack_size / 1024),
+            None => write!(f, "{:>4}/{}", "?", self.stack_size / 1024),
+        }
+    }
+}
+
+/// Calls `f` with every thread of the scheduler: the running thread, then the
+/// ready, sleeping and joining threads, then idle
+fn for_each_thread<F>(scheduler: &Scheduler, mut f: F)
+    where F: FnMut(&KThread)
+{
+    if let Some(ref thread) = scheduler.current {
+        f(thread);
+    }
+    for thread in scheduler.threads.iter()
+        .chain(scheduler.sleeping.iter())
+        .chain(scheduler.blocked.iter())
+    {
+        f(thread);
+    }
+    f(&scheduler.idle);
+}
+
+/// Returns a snapshot of every thread on the current CPU
+pub fn threads() -> Vec<ThreadInfo> {
+    let mut infos = Vec::new();
+    loop {
+        // Allocating with the scheduler locked could deadlock, so make room
+        // first and try again if threads were added in the meantime
+        let count = {
+            let lock = current().sched.lock();
+            let mut count = 0;
+            for_each_thread(&lock, |_| count += 1);
+            count
+        };
+        infos.reserve(count);
+
+        let lock = current().sched.lock();
+        let mut count = 0;
+        for_each_thread(&lock, |_| count += 1);
+        if count <= infos.capacity() {
+            for_each_thread(&lock, |thread| infos.push(ThreadInfo::new(thread)));
+            return infos;
+        }
+    }
+}
+
+/// Prints every thread on the current CPU over serial as TAP diagnostics
+pub fn print_threads() {
+    let infos = threads();
+    serial_println!("# {}", HEADER);
+    for info in infos.iter() {
+        serial_println!("# {}", info);
+    }
+}
+
+#[cfg(feature = "test")]
+pub mod tests {
+    use core::ptr;
+    use tap::TestGroup;
+    use super::super::{current_id, Builder, State};
+    use super::threads;
+
+    pub fn run() {
+        let mut tap = TestGroup::new(3);
+        tap.diagnostic("Testing thread snapshots");
+
+        let infos = threads();
+        let id = current_id();
+        tap.assert_tap(infos.iter().any(|info| info.id == id && info.state == State::Running),
+                       "Running thread is missing from the snapshot");
+
+        let handle = Builder::new()
+            .name("deep")
+            .spawn(|| 0)
+            .expect("Could not spawn thread");
+        let id = handle.id();
+        let found = threads().into_iter().find(|info| info.id == id);
+        tap.assert_tap(found.map_or(false, |info| {
+                           info.state == State::Ready &&
+                               info.name.as_ref().map_or(false, |name| &**name == "deep")
+                       }),
+                       "Spawned thread is missing from the snapshot");
+        handle.join();
+
+        let handle = Builder::new()
+            .spawn(|| {
+                let mut buffer = [0u8; 8 * 1024];
+                for byte in buffer.iter_mut() {
+                    unsafe { ptr::write_volatile(byte, 1) };
+                }
+                let id = current_id();
+                let info = threads().into_iter().find(|info| info.id == id);
+                match info.and_then(|info| info.stack_used.map(|used| (used, info.stack_size))) {
+                    Some((used, size)) if used <= size => used,
+                    _ => 0,
+                }
+            })
+            .expect("Could not spawn thread");
+        let used = handle.join();
+        tap.assert_tap(used >= 8 * 1024, "Stack usage was not measured");
+    }
+}
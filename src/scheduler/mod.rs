@@ -9,45 +9,221 @@
 #![allow(unused)]
 //! The ESALP Scheduler™
 //!
-//! Round robin scheduler and threading
+//! Priority scheduler and threading. Threads of the same priority are run
+//! round robin.
 
 use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::mem;
 
-use interrupts::{Context, SLEEP_INT};
+use interrupts::{Context, SLEEP_INT, JOIN_INT};
+use memory::SlabBox;
 use smp::current;
 
-use self::thread::{KThread, State, TICKS};
+use self::thread::{KThread, State, ExitStatus};
+use self::run_queue::RunQueues;
+
+pub use self::thread::{Priority, State};
+pub use self::builder::{Builder, SpawnError, DEFAULT_STACK_PAGES};
+pub use self::info::{ThreadInfo, threads, print_threads};
+
+/// The exit status of a thread that was killed
+pub const KILLED: usize = !0;
 
 mod thread;
+mod run_queue;
+mod builder;
+mod info;
 
-/// Basic round-robin scheduler
+/// Priority scheduler with one round-robin queue per priority
 pub struct Scheduler {
     // State::Ready
-    threads: VecDeque<KThread>,
+    threads: RunQueues,
     // State::Sleeping -- delta queue
-    sleeping: VecDeque<KThread>,
+    sleeping: VecDeque<SlabBox<KThread>>,
     // None => current == idle
-    current: Option<KThread>,
-    idle: KThread,
+    current: Option<SlabBox<KThread>>,
+    idle: SlabBox<KThread>,
+    // State::Joining
+    blocked: VecDeque<SlabBox<KThread>>,
+    // Threads that have exited. Their stacks cannot be freed while running on
+    // them, and the heap cannot be used in interrupt handlers, so they are
+    // freed later by `reap`.
+    exited: VecDeque<SlabBox<KThread>>,
 }
 
 impl Scheduler {
     pub fn new() -> Scheduler {
         unsafe { Scheduler {
-            threads: VecDeque::new(),
+            threads: RunQueues::new(),
             sleeping: VecDeque::new(),
             current: Some(KThread::main()),
             idle: KThread::idle(),
+            blocked: VecDeque::new(),
+            exited: VecDeque::new(),
         }}
     }
+
+    /// Returns the number of threads that every queue needs room for after
+    /// `count` more threads are added
+    fn needed(&self, count: usize) -> usize {
+        // Every thread but idle is either queued or running
+        self.threads.len() + self.sleeping.len() + self.blocked.len() +
+            self.exited.len() + 1 + count
+    }
+
+    /// Returns true if every queue has room for `total` threads, so that
+    /// moving threads between queues in interrupt handlers never allocates.
+    fn has_room(&self, total: usize) -> bool {
+        self.threads.capacity() >= total && self.sleeping.capacity() >= total &&
+            self.blocked.capacity() >= total && self.exited.capacity() >= total
+    }
+
+    /// Moves every queued thread into `queues`, which must have room for
+    /// them, and leaves the old queues in it.
+    fn replace_queues(&mut self, queues: &mut Queues) {
+        self.threads.move_into(&mut queues.threads);
+        queues.sleeping.extend(self.sleeping.drain(..));
+        queues.blocked.extend(self.blocked.drain(..));
+        queues.exited.extend(self.exited.drain(..));
+        mem::swap(&mut self.threads, &mut queues.threads);
+        mem::swap(&mut self.sleeping, &mut queues.sleeping);
+        mem::swap(&mut self.blocked, &mut queues.blocked);
+        mem::swap(&mut self.exited, &mut queues.exited);
+    }
 }
 
-/// Create a new thread that will start with the `start` function
-pub fn add(start: extern "C" fn()) -> Result<(), &'static str>{
-    let thread = KThread::new(start)?;
+/// Empty queues for the scheduler. The heap cannot be used with the scheduler
+/// locked, so larger queues are allocated first and swapped in.
+struct Queues {
+    threads: RunQueues,
+    sleeping: VecDeque<SlabBox<KThread>>,
+    blocked: VecDeque<SlabBox<KThread>>,
+    exited: VecDeque<SlabBox<KThread>>,
+}
 
-    current().sched.lock().threads.push_back(thread);
-    Ok(())
+impl Queues {
+    /// Allocates queues with room for `total` threads, or returns `None` if
+    /// the heap is out of memory
+    fn with_capacity(total: usize) -> Option<Queues> {
+        let queue = || {
+            let mut queue = VecDeque::new();
+            queue.try_reserve(total).ok().map(|_| queue)
+        };
+        Some(Queues {
+            threads: RunQueues::with_capacity(total)?,
+            sleeping: queue()?,
+            blocked: queue()?,
+            exited: queue()?,
+        })
+    }
+
+    /// Returns the number of threads that every queue has room for
+    fn capacity(&self) -> usize {
+        self.threads.capacity()
+            .min(self.sleeping.capacity())
+            .min(self.blocked.capacity())
+            .min(self.exited.capacity())
+    }
+}
+
+/// A handle to a thread that can be used to wait for it to exit. Dropping
+/// the handle detaches the thread.
+pub struct JoinHandle {
+    status: Arc<ExitStatus>,
+}
+
+impl JoinHandle {
+    /// Returns the id of the thread
+    pub fn id(&self) -> usize {
+        self.status.id()
+    }
+
+    /// Returns the exit status of the thread, or `None` if it is still
+    /// running
+    pub fn try_join(&self) -> Option<usize> {
+        self.status.get()
+    }
+
+    /// Blocks until the thread exits, and returns its exit status
+    pub fn join(self) -> usize {
+        if let Some(status) = self.status.get() {
+            return status;
+        }
+        unsafe {
+            asm!("int $0"
+                 :: "i"(JOIN_INT), "{rax}"(&*self.status as *const ExitStatus as usize)
+                 : "memory"
+                 : "volatile");
+        }
+        reap();
+        self.status.get().expect("Joined thread has not exited")
+    }
+}
+
+/// Create a new thread that will start with the `start` function. The value
+/// returned by `start` is the thread's exit status.
+pub fn add(start: extern "C" fn() -> usize) -> Result<JoinHandle, SpawnError> {
+    add_with_priority(start, Priority::Normal)
+}
+
+/// Create a new thread of the given priority that will start with the
+/// `start` function
+pub fn add_with_priority(start: extern "C" fn() -> usize, priority: Priority)
+    -> Result<JoinHandle, SpawnError>
+{
+    Builder::new().priority(priority).spawn(move || start())
+}
+
+/// Create a new thread that runs the closure `f`, with the defaults of
+/// `Builder`. The value returned by `f` is the thread's exit status.
+pub fn spawn<F>(f: F) -> Result<JoinHandle, SpawnError>
+    where F: FnOnce() -> usize + Send + 'static
+{
+    Builder::new().spawn(f)
+}
+
+/// Adds a new thread to the run queues
+fn queue(thread: SlabBox<KThread>) -> Result<JoinHandle, SpawnError> {
+    let handle = JoinHandle { status: thread.exit_status.clone() };
+    reap();
+
+    // The old queues are freed after the lock is released
+    let mut queues: Option<Queues> = None;
+    loop {
+        let needed = {
+            let mut lock = current().sched.lock();
+            let needed = lock.needed(1);
+            if !lock.has_room(needed) {
+                if let Some(ref mut queues) = queues {
+                    if queues.capacity() >= needed {
+                        lock.replace_queues(queues);
+                    }
+                }
+            }
+            if lock.has_room(needed) {
+                lock.threads.push(thread);
+                return Ok(handle);
+            }
+            needed
+        };
+        // Threads may be added while the queues are allocated, so check again
+        queues = Some(Queues::with_capacity(needed).ok_or(SpawnError::OutOfMemory)?);
+    }
+}
+
+/// Frees the threads that have exited.
+///
+/// This must be called from a thread, not an interrupt handler. Each thread
+/// is freed outside of the scheduler's lock.
+pub fn reap() {
+    loop {
+        let thread = current().sched.lock().exited.pop_front();
+        match thread {
+            Some(thread) => drop(thread),
+            None => break,
+        }
+    }
 }
 
 /// Yield the thread that `current_stack` belongs to to a new thread.
@@ -60,17 +236,19 @@ pub fn sched_yield(current_stack: &'static Context) -> &'static Context {
         ref mut sleeping,
         ref mut current,
         ref mut idle,
+        blocked: _,
+        exited: _,
     } = &mut *lock;
 
     let mut current_thread = current.take().unwrap();
-    let mut next_thread = threads.pop_front();
+    let mut next_thread = threads.pop();
 
     let ret = {
         let next = next_thread.as_mut().unwrap_or(idle);
         current_thread.swap(current_stack, next)
     };
 
-    threads.push_back(current_thread);
+    threads.push(current_thread);
 
     *current = next_thread;
     ret
@@ -88,11 +266,13 @@ pub fn sched_sleep(current_stack: &'static Context, time: u8) -> &'static Contex
         ref mut sleeping,
         ref mut current,
         ref mut idle,
+        blocked: _,
+        exited: _,
     } = &mut *lock;
 
     // first, swap out with a new thread
     let mut current_thread = current.take().unwrap();
-    let mut next_thread = threads.pop_front();
+    let mut next_thread = threads.pop();
 
     let ret = {
         let next = next_thread.as_mut().unwrap_or(idle);
@@ -125,18 +305,73 @@ pub fn sched_sleep(current_stack: &'static Context, time: u8) -> &'static Contex
     ret
 }
 
-/// Remove the current thread from the scheduler and reschedule
-pub fn sched_exit(current_stack: &'static Context) -> &'static Context {
+/// Block the current thread until the thread that `status` belongs to exits
+///
+/// # Safety
+/// `status` must be the address of an `ExitStatus` that is kept alive by the
+/// current thread
+pub unsafe fn sched_join(current_stack: &'static Context, status: usize)
+    -> &'static Context
+{
+    let status = &*(status as *const ExitStatus);
+    let mut lock = current().sched.lock();
+    let &mut Scheduler {
+        ref mut threads,
+        ref mut sleeping,
+        ref mut current,
+        ref mut idle,
+        ref mut blocked,
+        exited: _,
+    } = &mut *lock;
+
+    // The thread may have exited since the joiner checked
+    if status.get().is_some() {
+        return current_stack;
+    }
+
+    let mut current_thread = current.take().unwrap();
+    let mut next_thread = threads.pop();
+
+    let ret = {
+        let next = next_thread.as_mut().unwrap_or(idle);
+        current_thread.swap(current_stack, next)
+    };
+    *current = next_thread;
+
+    current_thread.state = State::Joining(status.id());
+    blocked.push_back(current_thread);
+
+    ret
+}
+
+/// Remove the current thread from the scheduler with the exit status
+/// `status` and reschedule
+pub fn sched_exit(current_stack: &'static Context, status: usize) -> &'static Context {
     let mut lock = current().sched.lock();
     let &mut Scheduler {
         ref mut threads,
         ref mut sleeping,
         ref mut current,
         ref mut idle,
+        ref mut blocked,
+        ref mut exited,
     } = &mut *lock;
 
     let mut current_thread = current.take().unwrap();
-    let mut next_thread = threads.pop_front();
+    current_thread.exit_status.set(status);
+
+    // Wake every thread joining this one, so they can be run next
+    let id = current_thread.id;
+    while let Some(index) = blocked.iter().position(|thread| match thread.state {
+        State::Joining(joining) => joining == id,
+        _ => false,
+    }) {
+        let mut thread = blocked.remove(index).unwrap();
+        thread.state = State::Ready;
+        threads.push(thread);
+    }
+
+    let mut next_thread = threads.pop();
 
     let ret = {
         let next = next_thread.as_mut().unwrap_or(idle);
@@ -144,12 +379,64 @@ pub fn sched_exit(current_stack: &'static Context) -> &'static Context {
     };
 
     *current = next_thread;
+    exited.push_back(current_thread);
 
     ret
 }
 
+/// Returns the id of the current thread if `address` is in the guard page
+/// below its stack. The idle thread is never reported.
+pub fn stack_overflow(address: usize) -> Option<usize> {
+    let lock = current().sched.lock();
+    lock.current.as_ref()
+        .and_then(|thread| if thread.in_guard_page(address) { Some(thread.id) } else { None })
+}
+
+/// Returns the id of the running thread
+pub fn current_id() -> usize {
+    let lock = current().sched.lock();
+    lock.current.as_ref().map_or(lock.idle.id, |thread| thread.id)
+}
+
+/// Changes the priority of the thread with the given id. The running thread
+/// keeps running until its time slice ends or it is preempted.
+pub fn set_priority(id: usize, priority: Priority) -> Result<(), &'static str> {
+    let mut lock = current().sched.lock();
+    let &mut Scheduler {
+        ref mut threads,
+        ref mut sleeping,
+        ref mut current,
+        ref mut idle,
+        ref mut blocked,
+        exited: _,
+    } = &mut *lock;
+
+    if idle.id == id {
+        return Err("The idle thread is always the lowest priority");
+    }
+    if let Some(ref mut thread) = *current {
+        if thread.id == id {
+            thread.priority = priority;
+            return Ok(());
+        }
+    }
+    if let Some(thread) = sleeping.iter_mut().chain(blocked.iter_mut())
+        .find(|thread| thread.id == id)
+    {
+        thread.priority = priority;
+        return Ok(());
+    }
+    // Requeue the thread behind the others of its new priority. Every queue
+    // has room for every thread, so this does not allocate.
+    let mut thread = threads.remove(id).ok_or("No thread has this id")?;
+    thread.priority = priority;
+    threads.push(thread);
+    Ok(())
+}
+
 /// Reduce the current thread's time slice by one tick. If it has no
-/// time left then yield to a new thread.
+/// time left, or a thread of a higher priority is ready, then yield to a new
+/// thread.
 pub fn tick(current_stack: &'static Context) -> &'static Context {
     let mut lock = current().sched.lock();
     let &mut Scheduler {
@@ -157,6 +444,8 @@ pub fn tick(current_stack: &'static Context) -> &'static Context {
         ref mut sleeping,
         ref mut current,
         ref mut idle,
+        blocked: _,
+        exited: _,
     } = &mut *lock;
 
     // update the sleeping thread list
@@ -167,27 +456,35 @@ pub fn tick(current_stack: &'static Context) -> &'static Context {
         let should_pop = sleeping.front()
             .map_or(false, |thread| thread.quanta == 0);
         if should_pop {
-            threads.push_back(sleeping.pop_front().unwrap());
+            threads.push(sleeping.pop_front().unwrap());
         } else {
             break;
         }
     }
 
     // now update the running thread
-    {
-        let mut running = current.as_mut().unwrap_or(idle);
-
-        running.quanta -= 1;
-        if running.quanta > 0 {
-            // continue with the current thread
-            return current_stack;
-        }
+    let expired = {
+        let running = current.as_mut().unwrap_or(idle);
+        running.ticks += 1;
+        running.quanta = running.quanta.saturating_sub(1);
+        running.quanta == 0
+    };
+    // A ready thread of a higher priority preempts the running thread, and
+    // any ready thread preempts idle
+    let preempted = match *current {
+        Some(ref thread) => threads.highest().map_or(false, |p| p > thread.priority),
+        None => !threads.is_empty(),
+    };
+    if !expired && !preempted {
+        // continue with the current thread
+        return current_stack;
     }
-    let mut next_thread = threads.pop_front();
+    let mut next_thread = threads.pop();
 
-    if next_thread.is_none() && current.is_none() {
-        // Only the idle thread can run
-        idle.quanta = TICKS;
+    if next_thread.is_none() {
+        // No other thread can run
+        let running = current.as_mut().unwrap_or(idle);
+        running.quanta = running.priority.time_slice();
         return current_stack;
     }
 
@@ -204,7 +501,7 @@ pub fn tick(current_stack: &'static Context) -> &'static Context {
     };
 
     if let Some(current) = current.take() {
-        threads.push_back(current);
+        threads.push(current);
     }
 
     *current = next_thread;
@@ -232,6 +529,11 @@ pub fn thread_sleep(time: u8) {
     }
 }
 
+/// Exit the current kernel thread with `status`
+pub fn thread_exit(status: usize) -> ! {
+    thread::exit(status)
+}
+
 /// Tests
 #[cfg(feature = "test")]
 pub mod tests {
@@ -239,6 +541,12 @@ pub mod tests {
     pub fn run() {
         test_yield();
         test_preempt();
+        test_priority();
+        test_starvation();
+        test_join();
+        test_spawn();
+        test_builder();
+        super::info::tests::run();
     }
 
     fn test_yield() {
@@ -249,8 +557,9 @@ pub mod tests {
         tap.ok(Some("Thread Returned"));
     }
 
-    extern "C" fn yield_thread() {
+    extern "C" fn yield_thread() -> usize {
         super::thread_yield();
+        0
     }
 
     use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
@@ -266,7 +575,130 @@ pub mod tests {
         }
         tap.ok(None);
     }
-    extern "C" fn preempt_thread() {
+    extern "C" fn preempt_thread() -> usize {
         SPIN.store(true, Ordering::Release);
+        0
+    }
+
+    static HIGH_RAN: AtomicBool = ATOMIC_BOOL_INIT;
+    static LOW_RAN: AtomicBool = ATOMIC_BOOL_INIT;
+    static STARVED_RAN: AtomicBool = ATOMIC_BOOL_INIT;
+    fn test_priority() {
+        use super::Priority;
+
+        let mut tap = TestGroup::new(2);
+        tap.diagnostic("Testing priorities");
+        super::add_with_priority(low_thread, Priority::Low).unwrap();
+        super::add_with_priority(high_thread, Priority::High).unwrap();
+
+        // The high priority thread runs first, and the low priority thread
+        // cannot run while this thread is ready
+        super::thread_yield();
+        tap.assert_tap(HIGH_RAN.load(Ordering::Acquire) && !LOW_RAN.load(Ordering::Acquire),
+                       "Threads did not run in order of priority");
+
+        // Once this thread is low priority the other one takes turns with it
+        let id = super::current_id();
+        super::set_priority(id, Priority::Low).unwrap();
+        super::thread_yield();
+        super::set_priority(id, Priority::Normal).unwrap();
+        tap.assert_tap(LOW_RAN.load(Ordering::Acquire),
+                       "Lowering the priority did not let the other thread run");
+    }
+    extern "C" fn high_thread() -> usize {
+        HIGH_RAN.store(true, Ordering::Release);
+        0
+    }
+    extern "C" fn low_thread() -> usize {
+        LOW_RAN.store(true, Ordering::Release);
+        0
+    }
+
+    fn test_join() {
+        let mut tap = TestGroup::new(3);
+        tap.diagnostic("Testing join");
+
+        let handle = super::add(return_thread).unwrap();
+        tap.assert_tap(handle.try_join().is_none(), "Thread exited before it ran");
+        tap.assert_tap(handle.join() == 42, "Returning did not give the exit status");
+
+        let status = super::add(exit_thread).unwrap().join();
+        tap.assert_tap(status == 7, "Exiting did not give the exit status");
+    }
+    extern "C" fn return_thread() -> usize {
+        // Make the joiner block
+        super::thread_sleep(2);
+        42
+    }
+    extern "C" fn exit_thread() -> usize {
+        super::thread_exit(7);
+    }
+
+    fn test_starvation() {
+        use super::Priority;
+        use super::run_queue::STARVATION_LIMIT;
+
+        let mut tap = TestGroup::new(1);
+        tap.diagnostic("Testing starvation");
+        super::add_with_priority(starved_thread, Priority::Low).unwrap();
+
+        // This thread is always ready, so the other one only runs once its
+        // priority has been passed over too often
+        let mut yields = 0;
+        while !STARVED_RAN.load(Ordering::Acquire) && yields <= STARVATION_LIMIT {
+            super::thread_yield();
+            yields += 1;
+        }
+        tap.assert_tap(STARVED_RAN.load(Ordering::Acquire),
+                       "Low priority thread was starved by a ready thread");
+    }
+    extern "C" fn starved_thread() -> usize {
+        STARVED_RAN.store(true, Ordering::Release);
+        0
+    }
+
+    fn test_spawn() {
+        use alloc::sync::Arc;
+        use alloc::vec::Vec;
+        use core::sync::atomic::AtomicUsize;
+
+        let mut tap = TestGroup::new(2);
+        tap.diagnostic("Testing spawn");
+
+        let shared = Arc::new(AtomicUsize::new(0));
+        let captured = shared.clone();
+        let values: Vec<usize> = (1..5).collect();
+        let status = super::spawn(move || {
+            captured.store(1, Ordering::Release);
+            values.iter().sum()
+        }).unwrap().join();
+        tap.assert_tap(status == 10 && shared.load(Ordering::Acquire) == 1,
+                       "Closure did not run with its captures");
+        tap.assert_tap(Arc::strong_count(&shared) == 1, "Closure captures were not dropped");
+    }
+
+    fn test_builder() {
+        use memory::MAX_STACK_PAGES;
+        use super::{Builder, SpawnError};
+
+        let mut tap = TestGroup::new(2);
+        tap.diagnostic("Testing the thread builder");
+
+        let zero = Builder::new().stack_pages(0).spawn(|| 0);
+        let huge = Builder::new().stack_pages(MAX_STACK_PAGES + 1).spawn(|| 0);
+        tap.assert_tap(zero.err() == Some(SpawnError::InvalidStackSize(0)) &&
+                           huge.err() == Some(SpawnError::InvalidStackSize(MAX_STACK_PAGES + 1)),
+                       "Invalid stack sizes were accepted");
+
+        // Far more than the default stack
+        let status = Builder::new()
+            .name("big stack")
+            .stack_pages(16)
+            .spawn(|| {
+                let buffer = [1u8; 32 * 1024];
+                buffer.iter().map(|&b| b as usize).sum()
+            })
+            .map(|handle| handle.join());
+        tap.assert_tap(status == Ok(32 * 1024), "Thread with a large stack did not run");
     }
 }
@@ -0,0 +1,121 @@
+// Copyright 2018 Calvin Lee
+// See the README.md file at the top-level directory of this
+// distribution.
+//
+// Licensed under the MIT license <LICENSE or
+// http://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Ready threads, queued by priority
+//!
+//! The highest priority thread is chosen first, but a queue that has been
+//! passed over `STARVATION_LIMIT` times has its first thread chosen instead,
+//! so that lower priorities are never starved.
+
+use alloc::collections::VecDeque;
+
+use memory::SlabBox;
+
+use super::thread::{KThread, Priority, PRIORITY_COUNT};
+
+/// The number of times a queue with ready threads can be passed over for a
+/// higher priority before its first thread is chosen
+pub const STARVATION_LIMIT: usize = 8;
+
+/// One round-robin queue of ready threads for each priority
+pub struct RunQueues {
+    /// Indexed by `Priority`
+    queues: [VecDeque<SlabBox<KThread>>; PRIORITY_COUNT],
+    /// The number of times each queue was passed over since it last ran a
+    /// thread or was empty
+    passed: [usize; PRIORITY_COUNT],
+}
+
+impl RunQueues {
+    pub fn new() -> RunQueues {
+        RunQueues {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            passed: [0; PRIORITY_COUNT],
+        }
+    }
+
+    /// Queues `thread` behind every other thread of its priority
+    pub fn push(&mut self, thread: SlabBox<KThread>) {
+        self.queues[thread.priority as usize].push_back(thread);
+    }
+
+    /// Removes the first thread of the highest priority, unless a lower
+    /// priority has been passed over too often
+    pub fn pop(&mut self) -> Option<SlabBox<KThread>> {
+        let starved = (0..PRIORITY_COUNT)
+            .find(|&i| self.passed[i] >= STARVATION_LIMIT && !self.queues[i].is_empty());
+        let chosen = starved.or_else(|| {
+            (0..PRIORITY_COUNT).rev().find(|&i| !self.queues[i].is_empty())
+        })?;
+
+        for i in 0..PRIORITY_COUNT {
+            if i == chosen || self.queues[i].is_empty() {
+                self.passed[i] = 0;
+            } else if i < chosen {
+                self.passed[i] += 1;
+            }
+        }
+        self.queues[chosen].pop_front()
+    }
+
+    /// Removes the thread with the given id
+    pub fn remove(&mut self, id: usize) -> Option<SlabBox<KThread>> {
+        for queue in self.queues.iter_mut() {
+            if let Some(index) = queue.iter().position(|thread| thread.id == id) {
+                return queue.remove(index);
+            }
+        }
+        None
+    }
+
+    /// Returns the highest priority of any queued thread
+    pub fn highest(&self) -> Option<Priority> {
+        Priority::all().iter().rev()
+            .find(|&&priority| !self.queues[priority as usize].is_empty())
+            .cloned()
+    }
+
+    /// Returns every queued thread, from the highest priority to the lowest
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = &'a SlabBox<KThread>> + 'a {
+        self.queues.iter().rev().flat_map(|queue| queue.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+
+    /// Returns the number of threads that can be queued at any priority
+    /// without allocating
+    pub fn capacity(&self) -> usize {
+        self.queues.iter().map(|queue| queue.capacity()).min().unwrap()
+    }
+
+    /// Returns empty queues with room for `total` threads at every priority,
+    /// or `None` if the heap is out of memory
+    pub fn with_capacity(total: usize) -> Option<RunQueues> {
+        let mut queues = RunQueues::new();
+        for queue in queues.queues.iter_mut() {
+            queue.try_reserve(total).ok()?;
+        }
+        Some(queues)
+    }
+
+    /// Moves every thread into `other`, keeping their order
+    pub fn move_into(&mut self, other: &mut RunQueues) {
+        for (queue, other) in self.queues.iter_mut().zip(other.queues.iter_mut()) {
+            other.extend(queue.drain(..));
+        }
+        other.passed = self.passed;
+        self.passed = [0; PRIORITY_COUNT];
+    }
+}
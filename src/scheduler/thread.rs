@@ -7,30 +7,119 @@
 // This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use interrupts::{Context, EXIT_INT};
-use memory::{alloc_stack, Stack};
-use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use alloc::sync::Arc;
+use interrupts::{self, Context, EXIT_INT};
+use memory::{PAGE_SIZE, alloc_stack, virt_to_phys, Stack, ObjectCache, SlabBox};
+use memory::{AddressSpace, switch_address_space};
+use super::SpawnError;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use core::intrinsics;
 use core::mem;
 
 /// The `id` of the next thread to be created
 static ID: AtomicUsize = ATOMIC_USIZE_INIT;
 /// The basic number of "ticks" each program gets to run
 pub const TICKS: u8 = 10;
+/// The number of priority levels
+pub const PRIORITY_COUNT: usize = 3;
+/// New stacks are filled with this word, so the deepest point that has been
+/// used can be found later
+const STACK_PAINT: usize = 0x57ac_57ac_57ac_57ac;
+
+/// The cache that thread control blocks are allocated from
+static THREAD_CACHE: ObjectCache<KThread> = ObjectCache::new("kthread");
 
 extern "C" {
-    static kstack_late_bottom: usize;
+    static kstack_bottom: usize;
     static kstack_top: usize;
 }
 
+/// What a thread is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum State {
     Running,
     Ready,
     Sleeping,
+    /// Waiting for the thread with the given id to exit
+    Joining(usize),
+}
+
+/// The exit status of a thread, shared by the thread and its `JoinHandle`
+pub struct ExitStatus {
+    id: usize,
+    exited: AtomicBool,
+    status: AtomicUsize,
+}
+
+impl ExitStatus {
+    fn new(id: usize) -> ExitStatus {
+        ExitStatus {
+            id: id,
+            exited: AtomicBool::new(false),
+            status: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the id of the thread
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Returns the status the thread exited with, or `None` if it has not
+    /// exited
+    pub fn get(&self) -> Option<usize> {
+        if self.exited.load(Ordering::Acquire) {
+            Some(self.status.load(Ordering::Relaxed))
+        } else {
+            None
+        }
+    }
+
+    /// Records that the thread exited with `status`
+    pub fn set(&self, status: usize) {
+        self.status.store(status, Ordering::Relaxed);
+        self.exited.store(true, Ordering::Release);
+    }
+}
+
+/// How urgently a thread should run. A ready thread runs before every ready
+/// thread of a lower priority, unless that priority has been passed over
+/// `STARVATION_LIMIT` times in a row. The idle thread only runs when no other
+/// thread is ready.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Background work
+    Low = 0,
+    /// The default priority
+    Normal = 1,
+    /// Interactive threads, which should mostly be sleeping
+    High = 2,
+}
+
+impl Priority {
+    /// Returns every priority, from lowest to highest
+    pub fn all() -> [Priority; PRIORITY_COUNT] {
+        [Priority::Low, Priority::Normal, Priority::High]
+    }
+
+    /// Returns the number of ticks a thread of this priority runs before it
+    /// is preempted. Lower priorities run for longer, because they are
+    /// preempted whenever a higher priority thread is ready.
+    pub fn time_slice(self) -> u8 {
+        match self {
+            Priority::Low => 2 * TICKS,
+            Priority::Normal => TICKS,
+            Priority::High => TICKS / 2,
+        }
+    }
 }
 
 pub struct KThread {
     pub id: usize,
+    pub name: Option<Arc<str>>,
     stack: Stack,
+    /// True if the stack was painted with `STACK_PAINT` when it was created
+    painted: bool,
     // Ready => Some(_), _ => None
     // XXX should this be a &'static _ or *const _ ? The former is wrong but
     // works and the latter is cumbersome but more explicit.
@@ -39,29 +128,48 @@ pub struct KThread {
     context: Option<&'static Context>,
     pub quanta: u8,
     pub state: State,
+    pub priority: Priority,
+    /// The address space the thread runs in, or `None` for the kernel's
+    pub address_space: Option<Arc<AddressSpace>>,
+    pub exit_status: Arc<ExitStatus>,
+    /// The number of ticks the thread has been running for
+    pub ticks: usize,
+    /// The number of times the thread has been switched to
+    pub switches: usize,
 }
 
 impl KThread {
-    /// Create a new thread with the given start point
+    /// Create a new thread with the given priority and a stack of
+    /// `stack_pages` pages that will call `start` with `argument`. The value
+    /// returned by `start` is the thread's exit status.
     ///
     /// # Side effects
-    /// Allocates a global stack for the given thread
-    pub fn new(start: extern "C" fn()) -> Result<KThread, &'static str> {
-        // for now create a 1-page stack
-        let stack = alloc_stack(1)?;
+    /// Allocates a global stack and a thread control block for the given
+    /// thread
+    pub fn new(start: extern "C" fn(usize) -> usize,
+               argument: usize,
+               priority: Priority,
+               stack_pages: usize) -> Result<SlabBox<KThread>, SpawnError>
+    {
+        let stack = alloc_stack(stack_pages)?;
+        // Painting the lazily backed part of the stack would map all of it
+        for word in (stack.mapped_bottom()..stack.top()).step_by(mem::size_of::<usize>()) {
+            unsafe { *(word as *mut usize) = STACK_PAINT };
+        }
         // now we must put the things we need on the stack.
         // In the meanwhile, grab an unbounded lifetime to our context
         let context = unsafe {
             // if the function ever returns, make it go to the thread exit point
             let mut stack_pointer = stack.top();
             stack_pointer -= mem::size_of::<extern "C" fn() -> !>();
-            *(stack_pointer as *mut extern "C" fn() -> !) = exit;
+            *(stack_pointer as *mut extern "C" fn() -> !) = thread_return;
 
             // Now we put on a fake interrupt context for returning to the thread
             let context_pointer = stack_pointer - mem::size_of::<Context>();
             {
                 let context = (context_pointer as *mut Context).as_mut().unwrap();
                 context.regs.zero();
+                context.regs.rdi = argument;
                 context.stack_frame.instruction_pointer = start as usize;
                 // TODO remove magic numbers, kernel code segment
                 context.stack_frame.code_segment = 0b1000;
@@ -73,29 +181,45 @@ impl KThread {
             (context_pointer as *const Context).as_ref().unwrap()
         };
 
-        Ok(KThread {
-            id: ID.fetch_add(1, Ordering::Relaxed),
+        let id = ID.fetch_add(1, Ordering::Relaxed);
+        THREAD_CACHE.alloc(KThread {
+            id: id,
+            name: None,
             stack: stack,
+            painted: true,
             context: Some(context),
-            quanta: TICKS,
+            quanta: priority.time_slice(),
             state: State::Ready,
-        })
+            priority: priority,
+            address_space: None,
+            exit_status: Arc::new(ExitStatus::new(id)),
+            ticks: 0,
+            switches: 0,
+        }).ok_or(SpawnError::OutOfMemory)
     }
     /// Return the current "main" thread.
     ///
     /// # Safety
     /// This function may only be called once on the main thread
-    pub unsafe fn main() -> KThread {
+    pub unsafe fn main() -> SlabBox<KThread> {
         assert_has_not_been_called!("The main kthread can be created only once!");
-        let top = &kstack_late_bottom as *const _ as usize;
+        let top = &kstack_bottom as *const _ as usize;
         let bottom = &kstack_top as *const _ as usize;
-        KThread {
-            id: ID.fetch_add(1, Ordering::Relaxed),
+        let id = ID.fetch_add(1, Ordering::Relaxed);
+        THREAD_CACHE.alloc(KThread {
+            id: id,
+            name: Some(Arc::from("main")),
             stack: Stack::new(bottom, top),
+            painted: false,
             context: None, /* current thread */
-            quanta: TICKS,
+            quanta: Priority::Normal.time_slice(),
             state: State::Running,
-        }
+            priority: Priority::Normal,
+            address_space: None,
+            exit_status: Arc::new(ExitStatus::new(id)),
+            ticks: 0,
+            switches: 0,
+        }).expect("Could not allocate the main thread control block")
     }
 
     /// Return the "idle" thread
@@ -105,9 +229,46 @@ impl KThread {
     ///
     /// # Side effects
     /// Allocates a global stack
-    pub unsafe fn idle() -> KThread {
+    pub unsafe fn idle() -> SlabBox<KThread> {
         assert_has_not_been_called!("The idle kthread can be created only once!");
-        Self::new(idle).unwrap()
+        let mut thread = Self::new(idle, 0, Priority::Low, 1).unwrap();
+        thread.name = Some(Arc::from("idle"));
+        thread
+    }
+
+    /// Returns true if `address` is in the guard page below the thread's
+    /// stack
+    pub fn in_guard_page(&self, address: usize) -> bool {
+        self.stack.in_guard_page(address)
+    }
+
+    /// Returns the size of the thread's stack in bytes
+    pub fn stack_size(&self) -> usize {
+        self.stack.top() - self.stack.bottom()
+    }
+
+    /// Returns the most bytes of its stack that the thread has used, or
+    /// `None` if the stack was not painted when it was created
+    ///
+    /// Lazily backed pages are not painted, so usage of them is only known to
+    /// the page.
+    pub fn stack_high_water(&self) -> Option<usize> {
+        if !self.painted {
+            return None;
+        }
+        // Lazily backed pages that were never touched are not mapped
+        let bottom = (self.stack.bottom()..self.stack.mapped_bottom())
+            .step_by(PAGE_SIZE)
+            .find(|&page| virt_to_phys(page).is_some())
+            .unwrap_or(self.stack.mapped_bottom());
+        if bottom < self.stack.mapped_bottom() {
+            return Some(self.stack.top() - bottom);
+        }
+        let deepest = (bottom..self.stack.top())
+            .step_by(mem::size_of::<usize>())
+            .find(|&word| unsafe { *(word as *const usize) } != STACK_PAINT)
+            .unwrap_or(self.stack.top());
+        Some(self.stack.top() - deepest)
     }
 
     /// Put `context` into the given thread and return the context
@@ -118,20 +279,43 @@ impl KThread {
         assert!(self.context.is_none());
         self.context = Some(context);
         self.state = State::Ready;
-        // give `other` the default time slice
+        // give `other` the time slice of its priority
         other.state = State::Running;
-        other.quanta = TICKS;
+        other.quanta = other.priority.time_slice();
+        other.switches += 1;
+        switch_address_space(other.address_space.as_ref());
         other.context.take().unwrap()
     }
 }
 
-extern "C" fn idle() {
+extern "C" fn idle(_: usize) -> usize {
     loop {
-        unsafe { asm!("hlt") };
+        // Idle only runs when no other thread can, so it frees exited
+        // threads. Interrupts are disabled so that it is never preempted
+        // while holding the heap's lock, which would starve the thread
+        // waiting for it.
+        unsafe { interrupts::disable() };
+        super::reap();
+        // `sti` takes effect after `hlt` starts, so no interrupt is missed
+        unsafe { asm!("sti
+                       hlt" :::: "volatile") };
     }
 }
 
-pub extern "C" fn exit() -> ! {
-    unsafe { asm!("int $0" :: "i"(EXIT_INT) :: "volatile") };
-    unreachable!();
+/// Exits the current thread with `status`
+pub fn exit(status: usize) -> ! {
+    unsafe {
+        asm!("int $0" :: "i"(EXIT_INT), "{rax}"(status) :: "volatile");
+        intrinsics::unreachable();
+    }
+}
+
+/// Threads return here from their start function, with their exit status
+/// still in `rax`
+#[naked]
+extern "C" fn thread_return() -> ! {
+    unsafe {
+        asm!("int $0" :: "i"(EXIT_INT) :: "volatile");
+        intrinsics::unreachable();
+    }
 }
@@ -12,6 +12,8 @@
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut, Drop};
 
+use spin::{Mutex, MutexGuard};
+
 use interrupts;
 
 /// While a lock for this struct is taken, interrutps are disabled
@@ -65,3 +67,77 @@ impl<'a, T: ?Sized> Drop for IrqGuard<'a, T> {
         }
     }
 }
+
+/// A spinlock that disables interrupts while it is held, so that an interrupt
+/// handler can never spin on a lock held by the code that it interrupted.
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T: 'a> {
+    // Always `Some` until the guard is dropped
+    guard: Option<MutexGuard<'a, T>>,
+    was_enabled: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(data: T) -> IrqMutex<T> {
+        IrqMutex {
+            inner: Mutex::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let enabled = interrupts::enabled();
+        if enabled {
+            unsafe { interrupts::disable() }
+        }
+        IrqMutexGuard {
+            guard: Some(self.inner.lock()),
+            was_enabled: enabled,
+        }
+    }
+
+    /// Takes the lock if it is free. Interrupts are disabled while the lock
+    /// is held, so on a single CPU this only fails if the caller holds it.
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let enabled = interrupts::enabled();
+        if enabled {
+            unsafe { interrupts::disable() }
+        }
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: Some(guard),
+                was_enabled: enabled,
+            }),
+            None => {
+                if enabled {
+                    unsafe { interrupts::enable() }
+                }
+                None
+            },
+        }
+    }
+}
+
+impl<'a, T> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+impl<'a, T> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for IrqMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // Unlock before interrupts can arrive
+        self.guard.take();
+        if self.was_enabled {
+            unsafe { interrupts::enable() }
+        }
+    }
+}
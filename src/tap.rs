@@ -51,6 +51,13 @@ impl TestGroup {
         }
     }
 
+    /// Reports a test that could not be run, and why
+    pub fn skip(&mut self, reason: &str) {
+        self.cur += 1;
+        serial_println!("ok {} # SKIP {}", self.cur, reason);
+        assert!(self.cur <= self.count);
+    }
+
     pub fn diagnostic(&self, msg: &str) {
         serial_println!("# {}", msg);
     }